@@ -0,0 +1,45 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use simplekv::{KvEngine, KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+const KEYS: usize = 20_000;
+const GENERATIONS: usize = 8;
+
+// A store whose live keys are spread over many generations
+fn populated_store(temp_dir: &TempDir, threads: usize) -> KvStore {
+    for gen in 0..GENERATIONS {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for i in (gen..KEYS).step_by(GENERATIONS) {
+            store.set(format!("key{}", i), "x".repeat(100)).unwrap();
+        }
+    }
+    KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::default().compaction_threads(threads),
+    )
+    .unwrap()
+}
+
+fn compaction(c: &mut Criterion) {
+    c.bench_function_over_inputs(
+        "compaction",
+        |b, &&threads| {
+            b.iter_with_setup(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let store = populated_store(&temp_dir, threads);
+                    (temp_dir, store)
+                },
+                |(_temp_dir, store)| store.compact().unwrap(),
+            )
+        },
+        &[1, 4],
+    );
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = compaction
+}
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use simplekv::{KvEngine, KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+const KEYS: usize = 20_000;
+
+fn populated_dir() -> TempDir {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::default().index_snapshot(true),
+    )
+    .unwrap();
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), "x".repeat(1000)).unwrap();
+    }
+    temp_dir
+}
+
+fn open(c: &mut Criterion) {
+    c.bench_function_over_inputs(
+        "open",
+        |b, &&snapshot| {
+            let temp_dir = populated_dir();
+            let options = KvStoreOptions::default().index_snapshot(snapshot);
+            b.iter_with_large_drop(|| {
+                KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap()
+            })
+        },
+        &[false, true],
+    );
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = open
+}
+criterion_main!(benches);
@@ -0,0 +1,57 @@
+use criterion::{criterion_group, criterion_main, Benchmark, Criterion};
+use simplekv::{KvEngine, KvStore};
+use tempfile::TempDir;
+
+// Values both below and above the size of the log's write buffer
+fn set(c: &mut Criterion) {
+    c.bench_function_over_inputs(
+        "set",
+        |b, &&len| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
+            let value = "x".repeat(len);
+            let mut i = 0;
+            b.iter(|| {
+                i += 1;
+                store.set(format!("key{}", i % 1000), value.clone()).unwrap();
+            })
+        },
+        &[100, 16 * 1024],
+    );
+}
+
+// Loading many fresh keys one `set` at a time vs through a `BulkLoader`
+fn load(c: &mut Criterion) {
+    const KEYS: usize = 1_000_000;
+    c.bench(
+        "load",
+        Benchmark::new("set", |b| {
+            b.iter_with_setup(
+                || TempDir::new().unwrap(),
+                |temp_dir| {
+                    let store = KvStore::open(temp_dir.path()).unwrap();
+                    for i in 0..KEYS {
+                        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+                    }
+                },
+            )
+        })
+        .with_function("bulk_loader", |b| {
+            b.iter_with_setup(
+                || TempDir::new().unwrap(),
+                |temp_dir| {
+                    let store = KvStore::open(temp_dir.path()).unwrap();
+                    let mut loader = store.bulk_loader().unwrap();
+                    for i in 0..KEYS {
+                        loader.set(format!("key{}", i), "value".to_owned()).unwrap();
+                    }
+                    loader.finish().unwrap();
+                },
+            )
+        })
+        .sample_size(10),
+    );
+}
+
+criterion_group!(benches, set, load);
+criterion_main!(benches);
@@ -1,7 +1,8 @@
 use clap::AppSettings;
-use simplekv::{KvClient, Result};
+use simplekv::{KvClient, Result, VersionInfo};
 use std::net::SocketAddr;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:6666";
@@ -16,7 +17,25 @@ const ADDRESS_FORMAT: &str = "IP:PORT";
 )]
 struct Opt {
     #[structopt(subcommand)]
-    command: Command,
+    command: Option<Command>,
+    #[structopt(long = "version-json", help = "Prints version information as JSON")]
+    version_json: bool,
+    #[structopt(
+        long,
+        help = "Retries connecting to the server N times before giving up",
+        value_name = "N",
+        default_value = "0",
+        raw(global = "true")
+    )]
+    retry: u32,
+    #[structopt(
+        long = "retry-interval",
+        help = "Milliseconds to wait before the first retry, doubled after each attempt",
+        value_name = "MS",
+        default_value = "100",
+        raw(global = "true")
+    )]
+    retry_interval: u64,
 }
 
 #[derive(StructOpt, Debug)]
@@ -25,6 +44,12 @@ enum Command {
     Get {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
+        #[structopt(
+            long,
+            help = "Prints this value instead of `key not found` if the key is missing",
+            value_name = "VALUE"
+        )]
+        default: Option<String>,
         #[structopt(
             long,
             help = "Sets the server address",
@@ -62,34 +87,84 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    #[structopt(name = "stats", about = "Print statistics of the server's store")]
+    Stats {
+        #[structopt(
+            long,
+            help = "Sets the output format",
+            value_name = "FORMAT",
+            default_value = "text",
+            raw(possible_values = "&[\"text\", \"json\"]")
+        )]
+        format: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 fn main() {
     let opt = Opt::from_args();
+    if opt.version_json {
+        println!("{}", serde_json::to_string(&VersionInfo::current()).unwrap());
+        return;
+    }
+    if opt.command.is_none() {
+        clap::Error::with_description(
+            "a subcommand is required",
+            clap::ErrorKind::MissingSubcommand,
+        )
+        .exit();
+    }
     if let Err(e) = run(opt) {
         eprintln!("{}", e);
         exit(1);
     }
 }
 
+fn connect(addr: SocketAddr, opt: &Opt) -> Result<KvClient> {
+    KvClient::builder()
+        .retry(opt.retry, Duration::from_millis(opt.retry_interval))
+        .connect(addr)
+}
+
 fn run(opt: Opt) -> Result<()> {
     match opt.command {
-        Command::Get { key, addr } => {
-            let mut client = KvClient::connect(addr)?;
-            if let Some(value) = client.get(key)? {
-                println!("{}", value);
-            } else {
-                println!("key not found");
+        Some(Command::Get { ref key, ref default, addr }) => {
+            let mut client = connect(addr, &opt)?;
+            match client.get(key.clone())?.or_else(|| default.clone()) {
+                Some(value) => println!("{}", value),
+                None => println!("key not found"),
             }
         }
-        Command::Set { key, value, addr } => {
-            let mut client = KvClient::connect(addr)?;
-            client.set(key, value)?;
+        Some(Command::Set { ref key, ref value, addr }) => {
+            let mut client = connect(addr, &opt)?;
+            client.set(key.clone(), value.clone())?;
+        }
+        Some(Command::Remove { ref key, addr }) => {
+            let mut client = connect(addr, &opt)?;
+            client.remove(key.clone())?;
         }
-        Command::Remove { key, addr } => {
-            let mut client = KvClient::connect(addr)?;
-            client.remove(key)?;
+        Some(Command::Stats { ref format, addr }) => {
+            let stats = connect(addr, &opt)?.stats()?;
+            if format == "json" {
+                println!("{}", serde_json::to_string(&stats)?);
+            } else {
+                println!("keys: {}", stats.keys);
+                println!("generations: {}", stats.generations);
+                println!("log_bytes: {}", stats.log_bytes);
+                println!("uncompacted: {}", stats.uncompacted);
+                println!("compactions: {}", stats.compactions);
+                println!("cache_hits: {}", stats.cache_hits);
+                println!("cache_bytes: {}", stats.cache_bytes);
+            }
         }
+        None => {}
     }
     Ok(())
 }
@@ -6,9 +6,9 @@ extern crate clap;
 use log::LevelFilter;
 use simplekv::*;
 use std::env::current_dir;
-use std::fs;
 use std::net::SocketAddr;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
 use simplekv::thread_pool::{SharedQueueThreadPool, ThreadPool};
 
@@ -29,12 +29,13 @@ enum Engine {
 struct Opt {
     #[structopt(
         long,
-        help = "Sets the listening address",
+        help = "Sets the listening address, can be repeated",
         value_name = "IP:PORT",
         raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+        raw(number_of_values = "1"),
         parse(try_from_str)
     )]
-    addr: SocketAddr,
+    addr: Vec<SocketAddr>,
     #[structopt(
         long,
         help = "Sets the storage engine",
@@ -42,58 +43,75 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long = "metrics-interval",
+        help = "Logs store metrics every MS milliseconds",
+        value_name = "MS"
+    )]
+    metrics_interval: Option<u64>,
+    #[structopt(long = "version-json", help = "Prints version information as JSON")]
+    version_json: bool,
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
-    if !engine.exists() {
-        return Ok(None);
-    }
-
-    match fs::read_to_string(engine)?.parse() {
-        Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!("The content of engine file is invalid: {}", e);
-            Ok(None)
+impl From<Engine> for EngineKind {
+    fn from(engine: Engine) -> EngineKind {
+        match engine {
+            Engine::kvstore => EngineKind::KvStore,
+            Engine::sled => EngineKind::Sled,
         }
     }
 }
 
-fn run_with_engine<E: KvEngine, P: ThreadPool>(engine: E, pool: P, addr: SocketAddr) -> Result<()> {
-    let server = KvServer::new(engine, pool);
-    server.run(addr)
+fn current_engine() -> Result<Option<Engine>> {
+    Ok(detect_engine(&current_dir()?)?.map(|kind| match kind {
+        EngineKind::KvStore => Engine::kvstore,
+        EngineKind::Sled => Engine::sled,
+    }))
+}
+
+fn run_with_engine<E: KvEngine, P: ThreadPool>(engine: E, pool: P, opt: &Opt) -> Result<()> {
+    let mut server = KvServer::new(engine, pool);
+    if let Some(ms) = opt.metrics_interval {
+        server = server.report_metrics(Duration::from_millis(ms));
+    }
+    server.run_multi(&opt.addr)
 }
 
 fn run(opt: Opt) -> Result<()> {
     let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+    // Before anything is logged or started, as the server won't run
+    if !EngineKind::from(engine).is_available() {
+        return Err(KvError::EngineUnavailable {
+            name: EngineKind::from(engine).to_string(),
+        });
+    }
     info!("kv-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr);
+    for addr in &opt.addr {
+        info!("Listening on {}", addr);
+    }
 
     let pool = SharedQueueThreadPool::new(4)?;
-    // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
 
     match engine {
-        Engine::kvstore => run_with_engine(KvStore::open(current_dir()?)?, pool, opt.addr),
-        Engine::sled => {
-            error!("not implement");
-            Ok(())
-        }
+        Engine::kvstore => run_with_engine(KvStore::open(current_dir()?)?, pool, &opt),
+        Engine::sled => Err(KvError::EngineUnavailable {
+            name: EngineKind::from(engine).to_string(),
+        }),
     }
 }
 
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
     let mut opt = Opt::from_args();
+    if opt.version_json {
+        println!("{}", serde_json::to_string(&VersionInfo::current()).unwrap());
+        return;
+    }
     let res = current_engine().and_then(move |curr_engine| {
         if opt.engine.is_none() {
             opt.engine = curr_engine;
         }
-        if curr_engine.is_some() && opt.engine != curr_engine {
-            error!("Wrong engine!");
-            exit(1);
-        }
         run(opt)
     });
     if let Err(e) = res {
@@ -1,54 +1,676 @@
 use crate::common::*;
-use crate::{KvError, Result};
+use crate::compress::decompress;
+use crate::{KeyMeta, KvError, Result, Stats, ValueVersion};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_json::de::IoRead;
 use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Collects connection options for a `KvClient`.
+#[derive(Debug, Clone, Default)]
+pub struct KvClientBuilder {
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    retries: u32,
+    retry_interval: Duration,
+    compress_min_bytes: Option<usize>,
+    max_value_bytes: Option<usize>,
+    /// Whether Nagle's algorithm stays on, off by default.
+    nagle: bool,
+    keepalive: bool,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+}
+
+impl KvClientBuilder {
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails requests whose response takes longer than `timeout` with `KvError::Timeout`.
+    ///
+    /// The connection is shut down then, since a late response would be taken for the
+    /// answer to the next request, so a timed out client has to be reconnected.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Retries connecting `retries` times, waiting `interval` before the first retry
+    /// and doubling it after each attempt.
+    pub fn retry(mut self, retries: u32, interval: Duration) -> Self {
+        self.retries = retries;
+        self.retry_interval = interval;
+        self
+    }
+
+    /// Asks the server to compress responses of at least `min_bytes`, which is worth it
+    /// for large `scan` results on slow links. The client decompresses them transparently.
+    pub fn compress_responses(mut self, min_bytes: usize) -> Self {
+        self.compress_min_bytes = Some(min_bytes);
+        self
+    }
+
+    /// Asks the server to refuse sending values longer than `max_bytes`, `get` then fails
+    /// with the server's error instead.
+    pub fn max_value_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_value_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Sends requests right away (`TCP_NODELAY`), on by default. Every request waits for its
+    /// response, so delaying small writes to batch them only adds latency.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nagle = !nodelay;
+        self
+    }
+
+    /// Has the OS probe idle connections (`SO_KEEPALIVE`), so a vanished server is noticed.
+    pub fn keepalive(mut self, keepalive: bool) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Size of the socket's send buffer (`SO_SNDBUF`), the OS default if not set.
+    pub fn send_buffer_size(mut self, bytes: usize) -> Self {
+        self.send_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Size of the socket's receive buffer (`SO_RCVBUF`), the OS default if not set.
+    pub fn recv_buffer_size(mut self, bytes: usize) -> Self {
+        self.recv_buffer_size = Some(bytes);
+        self
+    }
+
+    pub fn connect<A: ToSocketAddrs>(self, addr: A) -> Result<KvClient> {
+        let mut interval = self.retry_interval;
+        let mut attempt = 0;
+        let stream = loop {
+            match self.connect_once(&addr) {
+                Ok(stream) => break stream,
+                Err(e) if attempt >= self.retries => {
+                    return Err(KvError::StringError(format!(
+                        "failed to connect after {} attempts: {}",
+                        attempt + 1,
+                        e
+                    )))
+                }
+                Err(_) => {
+                    thread::sleep(interval);
+                    interval = (interval * 2).min(MAX_RETRY_INTERVAL);
+                    attempt += 1;
+                }
+            }
+        };
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        stream.set_nodelay(!self.nagle)?;
+        if self.keepalive {
+            set_socket_option(&stream, SocketOption::KeepAlive, 1)?;
+        }
+        if let Some(bytes) = self.send_buffer_size {
+            set_socket_option(&stream, SocketOption::SendBuffer, bytes)?;
+        }
+        if let Some(bytes) = self.recv_buffer_size {
+            set_socket_option(&stream, SocketOption::RecvBuffer, bytes)?;
+        }
+        let mut client = KvClient::from_stream(stream)?;
+        if self.compress_min_bytes.is_some() || self.max_value_bytes.is_some() {
+            client.hello(self.compress_min_bytes, self.max_value_bytes)?;
+        }
+        Ok(client)
+    }
+
+    /// A pool of up to `max` connections to `addr`, each opened with these options.
+    pub fn pool<A: ToSocketAddrs>(self, addr: A, max: usize) -> Result<KvClientPool> {
+        Ok(KvClientPool {
+            addrs: addr.to_socket_addrs()?.collect(),
+            builder: self,
+            max: max.max(1),
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            returned: Condvar::new(),
+        })
+    }
+
+    fn connect_once<A: ToSocketAddrs>(&self, addr: &A) -> io::Result<TcpStream> {
+        let timeout = match self.connect_timeout {
+            Some(timeout) => timeout,
+            None => return TcpStream::connect(addr),
+        };
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any address")
+        }))
+    }
+}
+
+fn decompress_response<T: DeserializeOwned>(rsp: MaybeCompressed<T>) -> Result<T> {
+    match rsp {
+        MaybeCompressed::Plain(rsp) => Ok(rsp),
+        MaybeCompressed::Compressed(CompressedResponse::Compressed(data)) => {
+            Ok(serde_json::from_slice(&decompress(&data)?)?)
+        }
+    }
+}
+
+/// Socket options `TcpStream` has no setters for.
+enum SocketOption {
+    KeepAlive,
+    SendBuffer,
+    RecvBuffer,
+}
+
+#[cfg(unix)]
+fn set_socket_option(stream: &TcpStream, option: SocketOption, value: usize) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let name = match option {
+        SocketOption::KeepAlive => libc::SO_KEEPALIVE,
+        SocketOption::SendBuffer => libc::SO_SNDBUF,
+        SocketOption::RecvBuffer => libc::SO_RCVBUF,
+    };
+    let value = value.min(libc::c_int::max_value() as usize) as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_socket_option(_stream: &TcpStream, _option: SocketOption, _value: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "socket options are only supported on Unix"))
+}
+
+/// Read timeouts are reported as `WouldBlock` on Unix and `TimedOut` on Windows.
+fn is_timeout(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        _ => false,
+    }
+}
 
 pub struct KvClient {
     reader: Deserializer<IoRead<BufReader<TcpStream>>>,
     writer: BufWriter<TcpStream>,
+    /// Prepended to every key sent to the server.
+    namespace: String,
+    /// Whether the server may send compressed responses.
+    compressed: bool,
+    /// The request in flight in non-blocking mode, `None` in blocking mode.
+    nonblocking: Option<InFlight>,
+}
+
+/// A request sent in non-blocking mode, kept so that repeating it after
+/// `KvError::WouldBlock` carries on where it stopped.
+#[derive(Default)]
+struct InFlight {
+    /// The serialized request, empty if none is in flight.
+    request: Vec<u8>,
+    /// Bytes of `request` sent so far.
+    written: usize,
+    /// Bytes of the response received so far.
+    response: Vec<u8>,
+    response_end: ValueEnd,
+}
+
+/// Finds where the first JSON value in a buffer ends, looking at each byte only once while
+/// the buffer fills up. A top-level number or literal only ends at the whitespace after it.
+#[derive(Default)]
+struct ValueEnd {
+    scanned: usize,
+    depth: usize,
+    started: bool,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl ValueEnd {
+    /// Scans the bytes of `buf` past those scanned before, returning the length of the
+    /// first value once it is complete. Malformed JSON is left for the parser to report.
+    fn find(&mut self, buf: &[u8]) -> Option<usize> {
+        while self.scanned < buf.len() {
+            let b = buf[self.scanned];
+            self.scanned += 1;
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if b == b'\\' {
+                    self.escaped = true;
+                } else if b == b'"' {
+                    self.in_string = false;
+                    if self.depth == 0 {
+                        return Some(self.scanned);
+                    }
+                }
+                continue;
+            }
+            match b {
+                b'"' => {
+                    self.started = true;
+                    self.in_string = true;
+                }
+                b'{' | b'[' => {
+                    self.started = true;
+                    self.depth += 1;
+                }
+                b'}' | b']' => {
+                    self.depth = self.depth.saturating_sub(1);
+                    if self.depth == 0 {
+                        return Some(self.scanned);
+                    }
+                }
+                b' ' | b'\t' | b'\n' | b'\r' => {
+                    if self.started && self.depth == 0 {
+                        return Some(self.scanned - 1);
+                    }
+                }
+                _ => self.started = true,
+            }
+        }
+        None
+    }
 }
 
 impl KvClient {
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_in_stream = TcpStream::connect(addr)?;
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        KvClient::from_stream(stream)
+    }
+
+    pub fn builder() -> KvClientBuilder {
+        KvClientBuilder::default()
+    }
+
+    fn from_stream(tcp_in_stream: TcpStream) -> Result<Self> {
         let tcp_out_stream = tcp_in_stream.try_clone()?;
 
         Ok(KvClient {
             reader: Deserializer::from_reader(BufReader::new(tcp_in_stream)),
             writer: BufWriter::new(tcp_out_stream),
+            namespace: String::new(),
+            compressed: false,
+            nonblocking: None,
         })
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let rsp = GetResponse::deserialize(&mut self.reader)?;
-        match rsp {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvError::StringError(msg)),
+    /// Prefixes every key with `prefix`, so clients of different namespaces can use the
+    /// same keys on one server without clashing. Only done on the client, the server
+    /// sees the prefixed keys.
+    pub fn with_namespace(mut self, prefix: impl Into<String>) -> Self {
+        self.namespace = prefix.into();
+        self
+    }
+
+    fn key(&self, key: String) -> String {
+        if self.namespace.is_empty() {
+            key
+        } else {
+            format!("{}{}", self.namespace, key)
         }
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
+    fn hello(
+        &mut self,
+        compress_min_bytes: Option<usize>,
+        max_value_bytes: Option<usize>,
+    ) -> Result<()> {
+        let req = Request::Hello {
+            compress_min_bytes,
+            max_value_bytes,
+        };
+        self.request(req)?.into_result()
+    }
+
+    /// Sends `req` as is and returns the server's response undecoded, e.g. to use requests
+    /// newer than the typed methods. Keys aren't prefixed with the namespace.
+    pub fn request(&mut self, req: Request) -> Result<Response> {
+        if let Request::Hello { compress_min_bytes, .. } = req {
+            // Compressed from the response to the hello on
+            self.compressed = compress_min_bytes.is_some();
+        }
+        if self.nonblocking.is_some() {
+            return self.request_nonblocking(&req);
+        }
+        serde_json::to_writer(&mut self.writer, &req)?;
         self.writer.flush()?;
-        let rsp = SetResponse::deserialize(&mut self.reader)?;
-        match rsp {
-            SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(msg) => Err(KvError::StringError(msg)),
+        self.read_response()
+    }
+
+    /// Makes requests fail with `KvError::WouldBlock` instead of waiting for the socket,
+    /// e.g. to drive the client from a poll loop.
+    ///
+    /// A request that failed with `WouldBlock` has to be repeated with the same arguments
+    /// until it completes, only then can another one be sent.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> Result<()> {
+        if matches!(&self.nonblocking, Some(in_flight) if !in_flight.request.is_empty()) {
+            return Err(KvError::StringError("a request is still in flight".to_owned()));
+        }
+        self.stream().set_nonblocking(nonblocking)?;
+        self.nonblocking = if nonblocking { Some(InFlight::default()) } else { None };
+        Ok(())
+    }
+
+    fn request_nonblocking(&mut self, req: &Request) -> Result<Response> {
+        let req = serde_json::to_vec(req)?;
+        let mut stream = self.writer.get_ref();
+        let in_flight = self.nonblocking.as_mut().expect("client is blocking");
+        if in_flight.request.is_empty() {
+            in_flight.request = req;
+            in_flight.written = 0;
+        } else if in_flight.request != req {
+            return Err(KvError::StringError(
+                "another request is still in flight, repeat it until it completes".to_owned(),
+            ));
+        }
+        while in_flight.written < in_flight.request.len() {
+            match stream.write(&in_flight.request[in_flight.written..]) {
+                Ok(n) => in_flight.written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(KvError::WouldBlock)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut buf = [0; 4096];
+        loop {
+            if let Some(len) = in_flight.response_end.find(&in_flight.response) {
+                in_flight.request.clear();
+                in_flight.response_end = ValueEnd::default();
+                let rest = in_flight.response.split_off(len);
+                let rsp = mem::replace(&mut in_flight.response, rest);
+                return if self.compressed {
+                    decompress_response(serde_json::from_slice(&rsp)?)
+                } else {
+                    Ok(serde_json::from_slice(&rsp)?)
+                };
+            }
+            match stream.read(&mut buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => in_flight.response.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(KvError::WouldBlock)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn read_response<T: DeserializeOwned>(&mut self) -> Result<T> {
+        match self.decode_response() {
+            Err(KvError::Serde(e)) if e.is_io() => match io::Error::from(e) {
+                ref e if is_timeout(e) => {
+                    let _ = self.stream().shutdown(Shutdown::Both);
+                    Err(KvError::Timeout)
+                }
+                e => Err(e.into()),
+            },
+            res => res,
+        }
+    }
+
+    fn decode_response<T: DeserializeOwned>(&mut self) -> Result<T> {
+        if !self.compressed {
+            return Ok(T::deserialize(&mut self.reader)?);
         }
+        decompress_response(MaybeCompressed::deserialize(&mut self.reader)?)
+    }
+
+    /// The underlying connection, e.g. to inspect socket options.
+    pub fn stream(&self) -> &TcpStream {
+        self.writer.get_ref()
+    }
+
+    /// Whether the connection is still open and has nothing unread, without blocking.
+    fn is_idle(&self) -> bool {
+        let stream = self.stream();
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        // Reading anything means the server closed the connection or it is out of sync
+        let idle = match stream.peek(&mut [0]) {
+            Ok(_) => false,
+            Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        };
+        stream.set_nonblocking(false).is_ok() && idle
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = self.key(key);
+        self.request(Request::Get { key })?.into_result()
+    }
+
+    /// Like `get`, but also returns the version of the value for `set_if_version`.
+    pub fn get_versioned(&mut self, key: String) -> Result<Option<(String, ValueVersion)>> {
+        let key = self.key(key);
+        self.request(Request::GetVersioned { key })?.into_result()
+    }
+
+    /// Sets `key` to `value` only if nobody wrote it since it was read with `version`,
+    /// returning whether it did.
+    pub fn set_if_version(
+        &mut self,
+        key: String,
+        value: String,
+        version: ValueVersion,
+    ) -> Result<bool> {
+        let key = self.key(key);
+        self.request(Request::SetIfVersion { key, value, version })?.into_result()
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let key = self.key(key);
+        self.request(Request::Set { key, value })?.into_result()
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let rsp = RemoveResponse::deserialize(&mut self.reader)?;
-        match rsp {
-            RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvError::StringError(msg)),
+        let key = self.key(key);
+        self.request(Request::Remove { key })?.into_result()
+    }
+
+    pub fn append(&mut self, key: String, suffix: String) -> Result<usize> {
+        let key = self.key(key);
+        self.request(Request::Append { key, suffix })?.into_result()
+    }
+
+    /// Moves the value of `from` to `to`, see `KvEngine::rename`.
+    pub fn rename(&mut self, from: String, to: String) -> Result<bool> {
+        let (from, to) = (self.key(from), self.key(to));
+        self.request(Request::Rename { from, to })?.into_result()
+    }
+
+    /// Adds `delta` to the value of `key`, see `KvEngine::increment_float`.
+    pub fn increment_float(&mut self, key: String, delta: f64) -> Result<f64> {
+        let key = self.key(key);
+        self.request(Request::IncrFloat { key, delta })?.into_result()
+    }
+
+    /// Sets all `pairs` in order, see `KvEngine::set_many`.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>, atomic: bool) -> Result<()> {
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, value)| (self.key(key), value))
+            .collect();
+        self.request(Request::BatchSet { pairs, atomic })?.into_result()
+    }
+
+    pub fn remove_if_exists(&mut self, key: String) -> Result<bool> {
+        let key = self.key(key);
+        self.request(Request::RemoveIfExists { key })?.into_result()
+    }
+
+    /// Makes the server sync every write acknowledged so far to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.request(Request::Sync)?.into_result()
+    }
+
+    /// Statistics of the server's engine, see `KvEngine::stats`.
+    pub fn stats(&mut self) -> Result<Stats> {
+        self.request(Request::Stats)?.into_result()
+    }
+
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let keys = keys.into_iter().map(|key| self.key(key)).collect();
+        self.request(Request::MultiRemove { keys })?.into_result()
+    }
+
+    pub fn meta(&mut self, key: String) -> Result<KeyMeta> {
+        let key = self.key(key);
+        self.request(Request::Meta { key })?.into_result()
+    }
+
+    /// All pairs whose key starts with `prefix`, in key order. The namespace is stripped
+    /// from the returned keys.
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let prefix = self.key(prefix);
+        let pairs: Vec<(String, String)> = self.request(Request::Scan { prefix })?.into_result()?;
+        let namespace = self.namespace.len();
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| (key[namespace..].to_owned(), value))
+            .collect())
+    }
+
+    /// Time `key` has left before it expires, `None` if it is absent or doesn't expire.
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        let key = self.key(key);
+        self.request(Request::Ttl { key })?.into_result()
+    }
+}
+
+/// Up to a fixed number of reusable connections to one server.
+///
+/// `get` hands out a connection, which goes back to the pool when the guard is dropped.
+pub struct KvClientPool {
+    addrs: Vec<SocketAddr>,
+    builder: KvClientBuilder,
+    max: usize,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<KvClient>,
+    /// Connections idle or handed out.
+    open: usize,
+}
+
+impl KvClientPool {
+    pub fn new<A: ToSocketAddrs>(addr: A, max: usize) -> Result<Self> {
+        KvClient::builder().pool(addr, max)
+    }
+
+    /// Hands out an idle connection, opens a new one if fewer than the maximum are open
+    /// or else waits for one to be returned. Idle connections the server closed are
+    /// replaced by new ones.
+    pub fn get(&self) -> Result<PooledClient> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(client) = state.idle.pop() {
+                if client.is_idle() {
+                    return Ok(PooledClient {
+                        pool: self,
+                        client: Some(client),
+                    });
+                }
+                state.open -= 1;
+                continue;
+            }
+            if state.open < self.max {
+                state.open += 1;
+                drop(state);
+                return match self.builder.clone().connect(&self.addrs[..]) {
+                    Ok(client) => Ok(PooledClient {
+                        pool: self,
+                        client: Some(client),
+                    }),
+                    Err(e) => {
+                        self.close_one();
+                        Err(e)
+                    }
+                };
+            }
+            state = self.returned.wait(state).unwrap();
+        }
+    }
+
+    fn close_one(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.returned.notify_one();
+    }
+}
+
+/// A connection of a `KvClientPool`, returned to it on drop.
+pub struct PooledClient<'a> {
+    pool: &'a KvClientPool,
+    client: Option<KvClient>,
+}
+
+impl PooledClient<'_> {
+    /// Closes the connection instead of returning it, e.g. after an error that may have
+    /// left a response unread.
+    pub fn discard(mut self) {
+        self.client = None;
+    }
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvClient;
+
+    fn deref(&self) -> &KvClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut KvClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        match self.client.take() {
+            Some(client) => {
+                self.pool.state.lock().unwrap().idle.push(client);
+                self.pool.returned.notify_one();
+            }
+            None => self.pool.close_one(),
         }
     }
 }
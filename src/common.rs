@@ -1,10 +1,135 @@
+use crate::{KeyMeta, KvError, Result, Stats, ValueVersion};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Bumped on every change to the wire format existing clients or servers can't handle.
+///
+/// 2: `Stats` gained the compaction, write and cache counters.
+pub const PROTOCOL_VERSION: u32 = 2;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     Get { key: String },
+    GetVersioned { key: String },
     Set { key: String, value: String },
+    /// Sets `key` only if it still holds the value `version` was read with.
+    SetIfVersion {
+        key: String,
+        value: String,
+        version: ValueVersion,
+    },
+    /// Sets the pairs in order, all or none of them if `atomic`.
+    BatchSet {
+        pairs: Vec<(String, String)>,
+        #[serde(default)]
+        atomic: bool,
+    },
     Remove { key: String },
+    RemoveIfExists { key: String },
+    Append { key: String, suffix: String },
+    IncrFloat { key: String, delta: f64 },
+    /// Moves the value of `from` to `to`, answered with whether `from` existed.
+    Rename { from: String, to: String },
+    MultiRemove { keys: Vec<String> },
+    Meta { key: String },
+    /// All pairs whose key starts with `prefix`, in key order.
+    Scan { prefix: String },
+    /// Sent first by clients wanting responses of at least `compress_min_bytes` compressed,
+    /// or no values longer than `max_value_bytes`.
+    Hello {
+        compress_min_bytes: Option<usize>,
+        #[serde(default)]
+        max_value_bytes: Option<usize>,
+    },
+    Ttl { key: String },
+    Sync,
+    Stats,
+}
+
+impl Request {
+    /// Names of all request types, see `name`.
+    pub const NAMES: &'static [&'static str] = &[
+        "get",
+        "get_versioned",
+        "set",
+        "set_if_version",
+        "batch_set",
+        "remove",
+        "remove_if_exists",
+        "append",
+        "incr_float",
+        "rename",
+        "multi_remove",
+        "meta",
+        "scan",
+        "hello",
+        "ttl",
+        "sync",
+        "stats",
+    ];
+
+    /// Short name of the request type, used in logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::GetVersioned { .. } => "get_versioned",
+            Request::Set { .. } => "set",
+            Request::SetIfVersion { .. } => "set_if_version",
+            Request::BatchSet { .. } => "batch_set",
+            Request::Remove { .. } => "remove",
+            Request::RemoveIfExists { .. } => "remove_if_exists",
+            Request::Append { .. } => "append",
+            Request::IncrFloat { .. } => "incr_float",
+            Request::Rename { .. } => "rename",
+            Request::MultiRemove { .. } => "multi_remove",
+            Request::Meta { .. } => "meta",
+            Request::Scan { .. } => "scan",
+            Request::Hello { .. } => "hello",
+            Request::Ttl { .. } => "ttl",
+            Request::Sync => "sync",
+            Request::Stats => "stats",
+        }
+    }
+}
+
+/// Sent in place of any response once compression was asked for in `Request::Hello`,
+/// holding the response compressed by `compress::compress`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CompressedResponse {
+    Compressed(String),
+}
+
+/// A response that may have been compressed.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MaybeCompressed<T> {
+    Compressed(CompressedResponse),
+    Plain(T),
+}
+
+/// Response to any request, see `KvClient::request`. The payload of `Ok` is left as JSON,
+/// its type depends on the request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Response {
+    Ok(serde_json::Value),
+    Err(String),
+}
+
+impl Response {
+    /// Decodes the payload as `T`, an `Err` response becomes `KvError::StringError`.
+    pub fn into_result<T: DeserializeOwned>(self) -> Result<T> {
+        match self {
+            Response::Ok(value) => Ok(serde_json::from_value(value)?),
+            Response::Err(msg) => Err(KvError::StringError(msg)),
+        }
+    }
+}
+
+/// Failure reply that can be sent in place of any response, all of them share its `Err` shape.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ErrorResponse {
+    Err(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -13,14 +138,98 @@ pub enum GetResponse {
     Err(String),
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetVersionedResponse {
+    Ok(Option<(String, ValueVersion)>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SetIfVersionResponse {
+    Ok(bool),
+    Err(String),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
     Err(String),
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BatchSetResponse {
+    Ok(()),
+    Err(String),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
     Err(String),
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RemoveIfExistsResponse {
+    Ok(bool),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AppendResponse {
+    Ok(usize),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IncrFloatResponse {
+    Ok(f64),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RenameResponse {
+    Ok(bool),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MultiRemoveResponse {
+    Ok(Vec<bool>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MetaResponse {
+    Ok(KeyMeta),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse {
+    Ok(Vec<(String, String)>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelloResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TtlResponse {
+    Ok(Option<Duration>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SyncResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(Stats),
+    Err(String),
+}
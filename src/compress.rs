@@ -0,0 +1,108 @@
+//! Compression of large responses, negotiated with `Request::Hello`.
+//!
+//! Compressed responses are sent as deflated JSON in base64, so they still fit in the
+//! JSON stream of the connection.
+
+use crate::{KvError, Result};
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec;
+
+const LEVEL: u8 = 6;
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const INVALID: u8 = 0xff;
+/// Value of every base64 digit by its byte, `INVALID` for other bytes.
+const DIGITS: [u8; 256] = {
+    let mut digits = [INVALID; 256];
+    let mut i = 0;
+    while i < BASE64.len() {
+        digits[BASE64[i] as usize] = i as u8;
+        i += 1;
+    }
+    digits
+};
+
+pub(crate) fn compress(data: &[u8]) -> String {
+    encode(&compress_to_vec(data, LEVEL))
+}
+
+pub(crate) fn decompress(encoded: &str) -> Result<Vec<u8>> {
+    let invalid = || KvError::Protocol("invalid compressed response".to_owned());
+    let deflated = decode(encoded).ok_or_else(invalid)?;
+    decompress_to_vec(&deflated).map_err(|_| invalid())
+}
+
+fn encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// `None` if `encoded` isn't valid base64.
+fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3 + 2);
+    for chunk in encoded.chunks(4) {
+        if chunk.len() < 2 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let digit = DIGITS[c as usize];
+            if digit == INVALID {
+                return None;
+            }
+            bits |= u32::from(digit) << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            decoded.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trip() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"f"), "Zg==");
+        assert_eq!(encode(b"fo"), "Zm8=");
+        assert_eq!(encode(b"foo"), "Zm9v");
+        assert_eq!(encode(b"foob"), "Zm9vYg==");
+        let data: Vec<u8> = (0..=255).collect();
+        for len in 0..data.len() {
+            assert_eq!(decode(&encode(&data[..len])).as_deref(), Some(&data[..len]));
+        }
+    }
+
+    #[test]
+    fn base64_invalid() {
+        assert_eq!(decode("Z"), None);
+        assert_eq!(decode("Zm9vY"), None);
+        assert_eq!(decode("Zm9v!g=="), None);
+        assert_eq!(decode("Zm=v"), None);
+        assert_eq!(decode("Zm9v\u{e9}"), None);
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let data = "value".repeat(1000);
+        assert_eq!(decompress(&compress(data.as_bytes()))?, data.as_bytes());
+        assert!(decompress("Zm9v").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Most keys tracked at once, the least accessed one makes room for a new key.
+const TRACKED_KEYS: usize = 1024;
+
+/// Approximate number of reads and writes of a key, see `KvStore::top_keys`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessStats {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    stats: AccessStats,
+    /// Accesses of the key this one replaced, counted towards its rank as it may have
+    /// been accessed that often before it was tracked.
+    inherited: u64,
+}
+
+impl Counters {
+    fn rank(&self) -> u64 {
+        self.inherited + self.stats.reads + self.stats.writes
+    }
+}
+
+/// Access counts of the most accessed keys, see `KvStoreOptions::track_access`.
+///
+/// Keeps at most `TRACKED_KEYS` keys by the space-saving algorithm: a key that isn't
+/// tracked replaces the one with the lowest count, inheriting that count. Keys accessed
+/// more often than that are always tracked.
+pub(crate) struct AccessTracker {
+    /// Only every `sample`th access is counted, as `sample` accesses of its key.
+    sample: u64,
+    accesses: AtomicU64,
+    counts: Mutex<HashMap<String, Counters>>,
+}
+
+impl AccessTracker {
+    pub(crate) fn new(sample: u64) -> AccessTracker {
+        AccessTracker {
+            sample: sample.max(1),
+            accesses: AtomicU64::new(0),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn record_read(&self, key: &str) {
+        self.record(key, |stats| &mut stats.reads);
+    }
+
+    pub(crate) fn record_write(&self, key: &str) {
+        self.record(key, |stats| &mut stats.writes);
+    }
+
+    fn record<F: Fn(&mut AccessStats) -> &mut u64>(&self, key: &str, counter: F) {
+        if !self.accesses.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sample) {
+            return;
+        }
+        let mut counts = self.counts.lock().unwrap();
+        if !counts.contains_key(key) {
+            let mut inherited = 0;
+            if counts.len() >= TRACKED_KEYS {
+                let evicted = counts
+                    .iter()
+                    .min_by_key(|(_, counters)| counters.rank())
+                    .map(|(key, _)| key.clone());
+                if let Some(counters) = evicted.and_then(|key| counts.remove(&key)) {
+                    inherited = counters.rank();
+                }
+            }
+            counts.insert(key.to_owned(), Counters { inherited, ..Counters::default() });
+        }
+        let counters = counts.get_mut(key).expect("key is tracked");
+        *counter(&mut counters.stats) += self.sample;
+    }
+
+    /// The `n` keys accessed most, most accessed first.
+    pub(crate) fn top(&self, n: usize) -> Vec<(String, AccessStats)> {
+        let counts = self.counts.lock().unwrap();
+        let mut keys: Vec<_> = counts
+            .iter()
+            .map(|(key, counters)| (counters.rank(), key))
+            .collect();
+        keys.sort_by(|(a_rank, a_key), (b_rank, b_key)| {
+            b_rank.cmp(a_rank).then(a_key.cmp(b_key))
+        });
+        keys.into_iter()
+            .take(n)
+            .map(|(_, key)| (key.clone(), counts[key].stats))
+            .collect()
+    }
+}
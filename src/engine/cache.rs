@@ -0,0 +1,80 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// LRU cache of values read from the log, keyed by `(version, start)` of the record.
+///
+/// A record position never changes its content, so a hit can't be stale: an overwrite
+/// points the index at a new position and the old entry simply becomes unreachable.
+pub(crate) struct ValueCache {
+    capacity: u64,
+    size: u64,
+    tick: u64,
+    hits: u64,
+    entries: HashMap<(u64, u64), (String, u64)>,
+    lru: BTreeMap<u64, (u64, u64)>,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: u64) -> ValueCache {
+        ValueCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            hits: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, pos: (u64, u64)) -> Option<String> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(&pos)?;
+        self.lru.remove(&entry.1);
+        self.lru.insert(tick, pos);
+        entry.1 = tick;
+        self.hits += 1;
+        Some(entry.0.clone())
+    }
+
+    pub(crate) fn insert(&mut self, pos: (u64, u64), value: String) {
+        let len = value.len() as u64;
+        if len > self.capacity {
+            return;
+        }
+        self.remove(pos);
+        while self.size + len > self.capacity {
+            let oldest = match self.lru.keys().next() {
+                Some(&tick) => tick,
+                None => break,
+            };
+            let pos = self.lru.remove(&oldest).unwrap();
+            self.remove(pos);
+        }
+        self.tick += 1;
+        self.size += len;
+        self.lru.insert(self.tick, pos);
+        self.entries.insert(pos, (value, self.tick));
+    }
+
+    pub(crate) fn remove(&mut self, pos: (u64, u64)) {
+        if let Some((value, tick)) = self.entries.remove(&pos) {
+            self.lru.remove(&tick);
+            self.size -= value.len() as u64;
+        }
+    }
+
+    /// Total length of the cached values.
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+        self.size = 0;
+    }
+}
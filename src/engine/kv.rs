@@ -1,324 +1,2661 @@
-use std::collections::{BTreeMap};
+use std::collections::{BTreeMap, BTreeSet};
+use fs2::FileExt;
+use std::error::Error;
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::mem;
+use std::slice;
+use std::thread;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
 
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{self, DeserializeSeed, IgnoredAny, IntoDeserializer, MapAccess, Visitor};
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
 
-use crate::engine::KvEngine;
+use super::access::{AccessStats, AccessTracker};
+use super::cache::ValueCache;
+use super::locks::KeyLocks;
+use super::options::EvictHook;
+use super::manifest::{verify_manifest, write_manifest};
+use super::snapshot::{read_snapshot, write_snapshot};
+use super::value_index::ValuePrefixIndex;
+use crate::engine::{
+    check_engine, expect_engine, parse_float, CompactionOrder, EngineKind, KeyMeta, KvEngine,
+    KvStoreOptions, ReadStats, Stats, SyncPolicy, ValueVersion,
+};
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
 use crate::{KvError, Result};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::cell::RefCell;
-use std::sync::Mutex;
+use std::sync::{Condvar, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crossbeam_skiplist::SkipMap;
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// Bytes replayed between two calls of the replay progress hook.
+const REPLAY_PROGRESS_INTERVAL: u64 = 1024 * 1024;
+const LOCK_FILE: &str = "LOCK";
+/// Records the log directory of a store keeping its logs outside the data directory.
+const LOG_DIR_FILE: &str = "LOG_DIR";
+/// Format tag of values nobody tagged.
+const RAW_FORMAT: u8 = 0;
+const MAX_GENERATION: u64 = u64::MAX / 2;
+/// errno of a write to a read-only filesystem, on Linux as well as macOS and the BSDs.
+const EROFS: i32 = 30;
+/// Commands with more payload than this go straight to the log writer, whose buffer
+/// (of `BufWriter`'s default size) they would bypass anyway.
+const SCRATCH_PAYLOAD_LIMIT: usize = 8 * 1024;
+/// Largest scratch buffer kept around between writes, a bigger one is freed after use.
+const SCRATCH_RETAIN: usize = 64 * 1024;
+/// Number of records read concurrently before they are written out by a parallel compaction.
+const COMPACTION_BATCH: usize = 4096;
+/// Keys the reaper checks at a time, it takes the writer lock once per batch.
+const REAP_BATCH: usize = 256;
+/// Longest `KvStore::close` waits for background tasks to let go of the writer.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Format of the logs this build writes, announced by a `LogHeader` at their start.
+const LOG_FORMAT: u32 = 2;
+/// Format of logs without a header, written before log formats had versions.
+const LEGACY_LOG_FORMAT: u32 = 1;
+/// How a serialized `LogHeader` starts, which no record does.
+const LOG_HEADER_PREFIX: &[u8] = br#"{"Format":"#;
 
-fn get_log_list(path: &Path) -> Result<Vec<u64>> {
-    let mut log_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
-        .collect();
+/// Milliseconds since the unix epoch, the unit of `expire_at`.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+pub(super) fn get_log_list(path: &Path) -> Result<Vec<u64>> {
+    let mut log_list = Vec::new();
+    for entry in fs::read_dir(&path)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension() != Some("log".as_ref()) {
+            continue;
+        }
+        match path.file_stem().and_then(OsStr::to_str).and_then(parse_generation) {
+            Some(gen) => log_list.push(gen),
+            None => warn!("Ignoring unexpected file {:?}", path),
+        }
+    }
     log_list.sort_unstable();
     Ok(log_list)
 }
 
-fn log_path(dir: &Path, version: u64) -> PathBuf {
-    dir.join(format!("{}.log", version))
-}
+/// Generation above every one used in `path`, counting anything named after a generation
+/// like leftovers of an interrupted write, so a new log never lands on an existing file.
+fn next_generation(path: &Path) -> Result<u64> {
+    let mut last = 0;
+    for entry in fs::read_dir(path)? {
+        let name = entry?.file_name();
+        let gen = name
+            .to_str()
+            .and_then(|name| name.split('.').next())
+            .and_then(parse_generation);
+        if let Some(gen) = gen {
+            last = last.max(gen);
+        }
+    }
+    Ok(last + 1)
+}
+
+/// Parses the name `log_path` gives generation `gen`, rejecting anything else like
+/// leading zeros or a sign, and generations too large to be followed by more.
+fn parse_generation(name: &str) -> Option<u64> {
+    if !name.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match name.parse::<u64>() {
+        Ok(gen) if gen <= MAX_GENERATION && gen.to_string() == name => Some(gen),
+        _ => None,
+    }
+}
+
+pub(super) fn log_path(dir: &Path, version: u64) -> PathBuf {
+    dir.join(format!("{}.log", version))
+}
+
+/// Background tasks holding the writer at the moment, which `KvStore::close` waits for.
+#[derive(Default)]
+struct BackgroundTasks {
+    running: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl BackgroundTasks {
+    /// Counts a task as running until the returned guard is dropped. Has to be called
+    /// before the task upgrades its reference to the writer, and the guard dropped after.
+    fn start(self: &Arc<Self>) -> BackgroundTask {
+        *self.running.lock().unwrap() += 1;
+        BackgroundTask(Arc::clone(self))
+    }
+
+    /// Waits until no task is running, `false` if there still is one at `deadline`.
+    fn wait_idle(&self, deadline: Instant) -> bool {
+        let running = self.running.lock().unwrap();
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let (running, _) = self
+            .idle
+            .wait_timeout_while(running, timeout, |running| *running > 0)
+            .unwrap();
+        *running == 0
+    }
+}
+
+struct BackgroundTask(Arc<BackgroundTasks>);
+
+impl Drop for BackgroundTask {
+    fn drop(&mut self) {
+        let mut running = self.0.running.lock().unwrap();
+        *running -= 1;
+        if *running == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+/// Compactions run on a pool of the store's own, see `KvStoreOptions::maintenance_threads`.
+struct Maintenance {
+    pool: SharedQueueThreadPool,
+    writer: Weak<Mutex<KvStoreWriter>>,
+    tasks: Arc<BackgroundTasks>,
+    /// Whether a compaction is queued and hasn't started yet.
+    compaction_scheduled: bool,
+}
+
+impl Maintenance {
+    fn schedule_compaction(&mut self) {
+        if self.compaction_scheduled {
+            return;
+        }
+        self.compaction_scheduled = true;
+        let writer = Weak::clone(&self.writer);
+        let tasks = Arc::clone(&self.tasks);
+        self.pool.spawn(move || {
+            let _task = tasks.start();
+            let writer = match writer.upgrade() {
+                Some(writer) => writer,
+                None => return,
+            };
+            let mut writer = writer.lock().unwrap();
+            if let Some(maintenance) = &mut writer.maintenance {
+                maintenance.compaction_scheduled = false;
+            }
+            // Done already if a compaction ran in the meantime
+            if writer.compaction_due() {
+                if let Err(e) = writer.compact() {
+                    error!("Background compaction failed: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Indexes the first `len` characters of every value in `index`.
+fn build_value_index(
+    len: usize,
+    index: &SkipMap<String, CommandIndex>,
+    reader: &KvStoreReader,
+) -> Result<ValuePrefixIndex> {
+    let value_index = ValuePrefixIndex::new(len);
+    for entry in index.iter() {
+        if let Command::Set { value, .. } = reader.read_command(entry.key(), *entry.value())? {
+            value_index.insert(entry.key(), &value);
+        }
+    }
+    Ok(value_index)
+}
+
+/// Replays every log in `log_dir` into a new index, `None` if one of them was compacted
+/// away while loading.
+fn load_all(log_dir: &Path) -> Result<Option<(Vec<u64>, SkipMap<String, CommandIndex>)>> {
+    let gen_list = get_log_list(log_dir)?;
+    let index = SkipMap::new();
+    for &gen in &gen_list {
+        let mut reader = match open_log(log_dir, gen) {
+            Ok(reader) => reader,
+            Err(KvError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let from = reader.records_start;
+        load(gen, from, &mut reader, &index, None, None)?;
+    }
+    Ok(Some((gen_list, index)))
+}
+
+/// Replays log `gen` from offset `from` into `index`, calling `progress` with the bytes
+/// replayed so far every `REPLAY_PROGRESS_INTERVAL` bytes.
+///
+/// Returns the bytes a compaction would reclaim and whether the log holds records of
+/// commands this version doesn't know.
+fn load(
+    gen: u64,
+    from: u64,
+    reader: &mut BufReaderWithIndex<File>,
+    index: &SkipMap<String, CommandIndex>,
+    tombstones: Option<&SkipMap<String, Instant>>,
+    progress: Option<&dyn Fn(u64)>,
+) -> Result<(u64, bool)> {
+    match reader.format {
+        // Format 2 only added the header, records are the same
+        LEGACY_LOG_FORMAT | LOG_FORMAT => {}
+        format => return Err(KvError::UnsupportedFormat { version: gen, format }),
+    }
+    let mut pos = reader.seek(SeekFrom::Start(from))?;
+    let mut stream = Deserializer::from_reader(reader).into_iter::<LogRecord>();
+    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
+    let mut unknown = false;
+    let mut next_report = from + REPLAY_PROGRESS_INTERVAL;
+    while let Some(record) = stream.next() {
+        let new_pos = from + stream.byte_offset() as u64;
+        if let Some(progress) = progress {
+            if new_pos >= next_report {
+                progress(new_pos - from);
+                next_report = new_pos + REPLAY_PROGRESS_INTERVAL;
+            }
+        }
+        let record = record.map_err(|e| {
+            if e.is_data() {
+                KvError::Corruption(format!("malformed record at {} of log {}: {}", pos, gen, e))
+            } else {
+                e.into()
+            }
+        })?;
+        let cmd = match record {
+            LogRecord::Known(cmd) => cmd,
+            LogRecord::Unknown(tag) => {
+                debug!("Skipping unknown `{}` record at {} of log {}", tag, pos, gen);
+                // A compaction would drop it, so the log has to be kept as it is
+                unknown = true;
+                pos = new_pos;
+                continue;
+            }
+        };
+        match cmd {
+            Command::Set { key, value, expire_at, written_at, format_tag } => {
+                if let Some(old_cmd) = index.get(&key) {
+                    uncompacted += old_cmd.value().len;
+                }
+                if let Some(tombstones) = tombstones {
+                    tombstones.remove(&key);
+                }
+                let cmd_pos: CommandIndex = (gen, pos..new_pos, value.len() as u64).into();
+                let cmd_pos = CommandIndex { expire_at, written_at, format_tag, ..cmd_pos };
+                index.insert(key, cmd_pos);
+            }
+            Command::Remove { key } => {
+                if let Some(old_cmd) = index.remove(&key) {
+                    uncompacted += old_cmd.value().len;
+                }
+                if let Some(tombstones) = tombstones {
+                    tombstones.insert(key, Instant::now());
+                }
+                // the "remove" command itself can be deleted in the next compaction
+                // so we add its length to `uncompacted`
+                uncompacted += new_pos - pos;
+            }
+        }
+        pos = new_pos;
+    }
+    Ok((uncompacted, unknown))
+}
+
+type OpenLogs = BTreeMap<u64, BufReaderWithIndex<File>>;
+
+/// Logs open by every clone of a store, so compactions can close the ones readers that
+/// went idle still hold.
+#[derive(Default)]
+struct ReaderRegistry {
+    readers: Mutex<Vec<Weak<Mutex<OpenLogs>>>>,
+}
+
+impl ReaderRegistry {
+    fn register(&self, logs: &Arc<Mutex<OpenLogs>>) {
+        let mut readers = self.readers.lock().unwrap();
+        readers.retain(|logs| logs.strong_count() > 0);
+        readers.push(Arc::downgrade(logs));
+    }
+
+    /// Closes the logs older than `safe_point` held by any reader, except the `keep`
+    /// newest of them.
+    fn close_stale(&self, safe_point: u64, keep: usize) {
+        let readers: Vec<_> = self
+            .readers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let mut stale = BTreeSet::new();
+        for logs in &readers {
+            stale.extend(logs.lock().unwrap().range(..safe_point).map(|(&version, _)| version));
+        }
+        let close: Vec<_> = stale.iter().rev().skip(keep).collect();
+        if close.is_empty() {
+            return;
+        }
+        info!("Closing {} stale logs still held by readers", close.len());
+        for logs in &readers {
+            let mut logs = logs.lock().unwrap();
+            for version in &close {
+                logs.remove(version);
+            }
+        }
+    }
+}
+
+struct KvStoreReader {
+    log_dir: Arc<PathBuf>,
+    curr_version: Arc<AtomicU64>,
+    readers: Arc<Mutex<OpenLogs>>,
+    registry: Arc<ReaderRegistry>,
+}
+
+impl KvStoreReader {
+    fn new(log_dir: Arc<PathBuf>, curr_version: Arc<AtomicU64>, readers: OpenLogs) -> Self {
+        let reader = KvStoreReader {
+            log_dir,
+            curr_version,
+            readers: Arc::new(Mutex::new(readers)),
+            registry: Arc::new(ReaderRegistry::default()),
+        };
+        reader.registry.register(&reader.readers);
+        reader
+    }
+
+    fn remove_timeout_log(&self) {
+        let mut readers = self.readers.lock().unwrap();
+        while !readers.is_empty() {
+            let version = *readers.keys().next().unwrap();
+            if version >= self.curr_version.load(Ordering::SeqCst) {
+                break;
+            }
+            readers.remove(&version);
+        }
+    }
+
+    /// Opens every log not open yet that reads may still need.
+    fn open_all(&self) -> Result<()> {
+        self.remove_timeout_log();
+        let safe_point = self.curr_version.load(Ordering::SeqCst);
+        for version in get_log_list(&self.log_dir)? {
+            if version < safe_point || self.is_open(version) {
+                continue;
+            }
+            let reader = match File::open(log_path(&self.log_dir, version)) {
+                Ok(file) => read_log_header(BufReaderWithIndex::new(file)?, version)?,
+                // Compacted away since it was listed
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            self.readers.lock().unwrap().insert(version, reader);
+        }
+        Ok(())
+    }
+
+    /// Whether log `version` is already open in this reader.
+    fn is_open(&self, version: u64) -> bool {
+        self.readers.lock().unwrap().contains_key(&version)
+    }
+
+    /// Reads the record of `key` at `cmd_index`, checking that it really is the record of `key`.
+    fn read_command(&self, key: &str, cmd_index: CommandIndex) -> Result<Command> {
+        let mismatch = || KvError::IndexMismatch {
+            key: key.to_owned(),
+            version: cmd_index.version,
+        };
+        let cmd: Command = self.read_and(cmd_index, |cmd_reader| {
+            serde_json::from_reader(cmd_reader).map_err(|e| {
+                if e.is_io() {
+                    KvError::Serde(e)
+                } else {
+                    mismatch()
+                }
+            })
+        })?;
+        match &cmd {
+            Command::Set { key: found, .. } | Command::Remove { key: found } if found != key => {
+                Err(mismatch())
+            }
+            _ => Ok(cmd),
+        }
+    }
+
+    fn read_and<F, R>(&self, cmd_pos: CommandIndex, f: F) -> Result<R>
+        where
+            F: FnOnce(io::Take<&mut BufReaderWithIndex<File>>) -> Result<R>,
+    {
+        self.remove_timeout_log();
+
+        // Take the reader out of the map so the lock isn't held while `f` runs.
+        // If `f` panics the reader is simply dropped and reopened by the next read.
+        let reader = self.readers.lock().unwrap().remove(&cmd_pos.version);
+        let mut reader = match reader {
+            Some(reader) => reader,
+            None => open_log(&self.log_dir, cmd_pos.version)?,
+        };
+        reader.seek(SeekFrom::Start(cmd_pos.start))?;
+        let result = f((&mut reader).take(cmd_pos.len));
+        self.readers.lock().unwrap().insert(cmd_pos.version, reader);
+        result
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        let reader = KvStoreReader {
+            log_dir: Arc::clone(&self.log_dir),
+            curr_version: Arc::clone(&self.curr_version),
+            readers: Arc::new(Mutex::new(BTreeMap::new())),
+            registry: Arc::clone(&self.registry),
+        };
+        self.registry.register(&reader.readers);
+        reader
+    }
+}
+
+/// Totals over the compactions since the store was opened.
+#[derive(Default)]
+struct CompactionCounters {
+    runs: u64,
+    time: Duration,
+    reclaimed_bytes: u64,
+    written_bytes: u64,
+}
+
+struct KvStoreWriter {
+    reader: KvStoreReader,
+    writer: BufWriterWithIndex<File>,
+    /// Reused to serialize commands, so each one reaches the log in a single write.
+    scratch: Vec<u8>,
+    curr_version: u64,
+    uncompacted: u64,
+    /// Logs holding records of commands this version doesn't know, which a compaction
+    /// would drop.
+    unknown_logs: BTreeSet<u64>,
+    generations: usize,
+    max_generations: Option<usize>,
+    max_value_bytes: Option<usize>,
+    format_tags: Vec<(String, u8)>,
+    compaction_ratio: Option<f64>,
+    compaction_cooldown: Option<Duration>,
+    last_compaction: Option<Instant>,
+    max_retained_generations: Option<usize>,
+    compaction_counters: CompactionCounters,
+    /// Bytes of records appended by writes since the store was opened.
+    written_bytes: u64,
+    /// Length of all logs but the active one.
+    sealed_bytes: u64,
+    log_dir: Arc<PathBuf>,
+    index: Arc<SkipMap<String, CommandIndex>>,
+    cache: Option<Arc<Mutex<ValueCache>>>,
+    tombstones: Arc<SkipMap<String, Instant>>,
+    tombstone_retention: Option<Duration>,
+    dedup_sets: bool,
+    sync_policy: SyncPolicy,
+    compaction_threads: usize,
+    compaction_order: CompactionOrder,
+    /// Keys expired or reaped since the last call of `KvStore::with_writer`.
+    evicted: Vec<String>,
+    /// Where to write the manifest on clean shutdown, if enabled.
+    manifest_dir: Option<PathBuf>,
+    /// Where to write the index snapshot on clean shutdown and compaction, if enabled.
+    snapshot_dir: Option<PathBuf>,
+    value_index: Option<Arc<ValuePrefixIndex>>,
+    access: Option<Arc<AccessTracker>>,
+    /// Pool running the compactions writes trigger, if enabled.
+    maintenance: Option<Maintenance>,
+    /// Set once `shut_down` ran, so dropping doesn't run it again.
+    closed: bool,
+    /// Locks on the data directory and a separate log directory, declared last so they
+    /// are released after `drop` ran.
+    _dir_locks: Vec<DirLock>,
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        if !self.closed {
+            if let Err(e) = self.shut_down(false) {
+                error!("Failed to shut down the store: {}", e);
+            }
+        }
+    }
+}
+
+impl KvStoreWriter {
+    /// Flushes the active log, or syncs it if `sync`, and writes everything the next open
+    /// expects from a clean shutdown.
+    fn shut_down(&mut self, sync: bool) -> Result<()> {
+        self.closed = true;
+        // Left behind, an unused active log would look like a crash to the next open
+        if self.writer.index == log_header_len() {
+            let file_path = log_path(&self.log_dir, self.curr_version);
+            if let Err(e) = fs::remove_file(&file_path) {
+                error!("{:?} cannot be deleted: {}", file_path, e);
+            }
+        }
+        if sync {
+            self.writer.sync_all()?;
+        } else {
+            self.writer.flush()?;
+        }
+        self.snapshot()?;
+        if let Some(meta_dir) = &self.manifest_dir {
+            write_manifest(meta_dir, &self.log_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Saves the index as of the records written so far, if enabled.
+    fn snapshot(&self) -> Result<()> {
+        let meta_dir = match &self.snapshot_dir {
+            Some(meta_dir) => meta_dir,
+            None => return Ok(()),
+        };
+        let generations = get_log_list(&self.log_dir)?
+            .into_iter()
+            .filter(|&gen| gen <= self.curr_version)
+            .map(|gen| {
+                if gen == self.curr_version {
+                    Ok((gen, self.writer.index))
+                } else {
+                    Ok((gen, fs::metadata(log_path(&self.log_dir, gen))?.len()))
+                }
+            })
+            .collect::<Result<_>>()?;
+        write_snapshot(
+            meta_dir,
+            generations,
+            self.uncompacted,
+            self.unknown_logs.iter().cloned().collect(),
+            &self.index,
+            self.tombstones.iter().map(|entry| entry.key().clone()),
+        )
+    }
+
+    fn invalidate(&self, cmd_index: CommandIndex) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().remove((cmd_index.version, cmd_index.start));
+        }
+    }
+
+    fn check_entry(&self, key: &str, value: &str) -> Result<()> {
+        // Values may be empty, an empty key is too easily a caller's mistake
+        if key.is_empty() {
+            return Err(KvError::InvalidKey("key is empty".to_owned()));
+        }
+        match self.max_value_bytes {
+            Some(max) if value.len() > max => Err(KvError::ValueTooLarge {
+                len: value.len(),
+                max,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Tag of the longest prefix of `key` given to `KvStoreOptions::format_for_prefix`.
+    fn format_for(&self, key: &str) -> u8 {
+        self.format_tags
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(RAW_FORMAT, |&(_, tag)| tag)
+    }
+
+    /// Sets `key`, tagging the value with `format_tag` or else the tag of its prefix.
+    fn set(
+        &mut self,
+        key: String,
+        value: String,
+        expire_at: Option<u64>,
+        format_tag: Option<u8>,
+    ) -> Result<()> {
+        self.check_entry(&key, &value)?;
+        self.record_write(&key);
+        let format_tag = format_tag.unwrap_or_else(|| self.format_for(&key));
+        if self.dedup_sets && expire_at.is_none() && self.holds(&key, &value, format_tag)? {
+            return Ok(());
+        }
+        let written_at = Some(now_millis());
+        let cmd = Command::Set { key, value, expire_at, written_at, format_tag };
+        let pos = self.writer.index;
+        self.write_command(&cmd)?;
+        self.index_set(cmd, pos..self.writer.index);
+        self.maybe_compact()
+    }
+
+    /// Sets all `pairs` with one write to the log, so either all of them are applied or none.
+    fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in &pairs {
+            self.check_entry(key, value)?;
+        }
+        for (key, _) in &pairs {
+            self.record_write(key);
+        }
+        let written_at = Some(now_millis());
+        let cmds: Vec<_> = pairs
+            .into_iter()
+            .map(|(key, value)| {
+                let format_tag = self.format_for(&key);
+                Command::Set { key, value, expire_at: None, written_at, format_tag }
+            })
+            .collect();
+        let ranges = self.write_commands(&cmds)?;
+        for (cmd, range) in cmds.into_iter().zip(ranges) {
+            self.index_set(cmd, range);
+        }
+        self.maybe_compact()
+    }
+
+    fn record_write(&self, key: &str) {
+        if let Some(access) = &self.access {
+            access.record_write(key);
+        }
+    }
+
+    /// Points the index at the `Set` record `cmd`, written to `range` of the active log.
+    fn index_set(&mut self, cmd: Command, range: Range<u64>) {
+        if let Command::Set { key, value, expire_at, written_at, format_tag } = cmd {
+            let cmd_pos: CommandIndex = (self.curr_version, range, value.len() as u64).into();
+            let cmd_pos = CommandIndex { expire_at, written_at, format_tag, ..cmd_pos };
+            if let Some(value_index) = &self.value_index {
+                value_index.insert(&key, &value);
+            }
+            self.index_insert(key, cmd_pos);
+        }
+    }
+
+    /// Drops `key` from the index once its `Remove` record, `len` bytes long, was written.
+    /// Drops `key` from the index after its removal of `len` bytes was logged. The key may
+    /// be gone already, e.g. removed by the reaper after it expired.
+    fn index_remove(&mut self, key: String, len: u64) {
+        if let Some(old_cmd) = self.index.remove(&key) {
+            if let Some(value_index) = &self.value_index {
+                value_index.remove(&key);
+            }
+            self.uncompacted += old_cmd.value().len;
+            self.invalidate(*old_cmd.value());
+        }
+        self.uncompacted += len;
+        if self.tombstone_retention.is_some() {
+            self.tombstones.insert(key, Instant::now());
+        }
+    }
+
+    fn index_insert(&mut self, key: String, cmd_pos: CommandIndex) {
+        self.tombstones.remove(&key);
+        let old_cmd = self.index.get(&key).map(|entry| *entry.value());
+        self.index.insert(key, cmd_pos);
+        // Only once the index moved on, see `KvStore::read_value`
+        if let Some(old_cmd) = old_cmd {
+            self.uncompacted += old_cmd.len;
+            self.invalidate(old_cmd);
+        }
+    }
+
+    /// Makes the records written so far durable as the sync policy demands.
+    fn sync(&mut self) -> Result<()> {
+        match self.sync_policy {
+            SyncPolicy::Flush => self.writer.flush()?,
+            SyncPolicy::Always => self.writer.sync_all()?,
+        }
+        Ok(())
+    }
+
+    /// Moves `value`, the value of `from`, to `to` with one write to the log, keeping its
+    /// expiry and format tag.
+    fn rename(
+        &mut self,
+        from: String,
+        to: String,
+        value: String,
+        expire_at: Option<u64>,
+        format_tag: u8,
+    ) -> Result<()> {
+        self.check_entry(&to, &value)?;
+        self.record_write(&from);
+        self.record_write(&to);
+        let written_at = Some(now_millis());
+        let set = Command::Set { key: to, value, expire_at, written_at, format_tag };
+        let cmds = vec![set, Command::remove(from)];
+        let ranges = self.write_commands(&cmds)?;
+        for (cmd, range) in cmds.into_iter().zip(ranges) {
+            match cmd {
+                Command::Remove { key } => self.index_remove(key, range.end - range.start),
+                cmd => self.index_set(cmd, range),
+            }
+        }
+        self.maybe_compact()
+    }
+
+    /// Rewrites `key` into the active log if its record is still the one at `cmd_pos`
+    /// holding `value` and is in an older log.
+    fn repair(&mut self, key: &str, value: &str, cmd_pos: CommandIndex) -> Result<()> {
+        let current = self.index.get(key).map(|entry| *entry.value());
+        if current != Some(cmd_pos) || cmd_pos.version >= self.curr_version {
+            return Ok(());
+        }
+        let cmd = Command::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            expire_at: cmd_pos.expire_at,
+            written_at: cmd_pos.written_at,
+            format_tag: cmd_pos.format_tag,
+        };
+        let range = self.write_commands(slice::from_ref(&cmd))?.remove(0);
+        self.index_set(cmd, range);
+        Ok(())
+    }
+
+    /// Removes `key` if it is still stored at `cmd_pos` and has expired.
+    fn expire(&mut self, key: String, cmd_pos: CommandIndex) -> Result<()> {
+        let current = self.index.get(&key).map(|entry| *entry.value());
+        if current != Some(cmd_pos) || !cmd_pos.expired(now_millis()) {
+            return Ok(());
+        }
+        let cmd = Command::remove(key);
+        let pos = self.writer.index;
+        self.write_command(&cmd)?;
+        if let Command::Remove { key } = cmd {
+            self.index.remove(&key);
+            if let Some(value_index) = &self.value_index {
+                value_index.remove(&key);
+            }
+            self.uncompacted += cmd_pos.len + self.writer.index - pos;
+            self.invalidate(cmd_pos);
+            self.evicted.push(key);
+        }
+        self.maybe_compact()
+    }
+
+    /// Removes `key` if its current value has expired.
+    fn expire_key(&mut self, key: &str) -> Result<()> {
+        match self.index.get(key).map(|entry| *entry.value()) {
+            Some(cmd_pos) => self.expire(key.to_owned(), cmd_pos),
+            None => Ok(()),
+        }
+    }
+
+    fn write_command(&mut self, cmd: &Command) -> Result<()> {
+        self.write_commands(slice::from_ref(cmd)).map(|_| ())
+    }
+
+    /// Appends `cmds` to the active log, returning where each of them went. On failure
+    /// (e.g. a full disk) whatever part of them was buffered or written is dropped again,
+    /// so the log stays consistent.
+    fn write_commands(&mut self, cmds: &[Command]) -> Result<Vec<Range<u64>>> {
+        let pos = self.writer.index;
+        let mut ranges = Vec::with_capacity(cmds.len());
+        let mut res = Ok(());
+        for cmd in cmds {
+            let start = self.writer.index;
+            res = if cmd.payload_len() > SCRATCH_PAYLOAD_LIMIT {
+                serde_json::to_writer(&mut self.writer, cmd).map_err(KvError::from)
+            } else {
+                self.scratch.clear();
+                serde_json::to_writer(&mut self.scratch, cmd)
+                    .map_err(KvError::from)
+                    .and_then(|_| Ok(self.writer.write_all(&self.scratch)?))
+            };
+            if res.is_err() {
+                break;
+            }
+            ranges.push(start..self.writer.index);
+        }
+        let res = res.and_then(|_| self.sync());
+        if self.scratch.capacity() > SCRATCH_RETAIN {
+            self.scratch = Vec::new();
+        }
+        match res {
+            Ok(()) => self.written_bytes += self.writer.index - pos,
+            Err(_) => {
+                if let Err(e) = self.writer.truncate(pos) {
+                    error!("Failed to roll back the log after a failed write: {}", e);
+                }
+            }
+        }
+        res.map(|_| ranges)
+    }
+
+    /// Whether `key` currently maps to `value`, only reading the log if the lengths match.
+    fn holds(&self, key: &str, value: &str, format_tag: u8) -> Result<bool> {
+        let cmd_pos = match self.index.get(key) {
+            Some(cmd_pos) => *cmd_pos.value(),
+            None => return Ok(false),
+        };
+        if cmd_pos.value_len != value.len() as u64
+            || cmd_pos.expire_at.is_some()
+            || cmd_pos.format_tag != format_tag
+        {
+            return Ok(false);
+        }
+        match self.reader.read_command(key, cmd_pos)? {
+            Command::Set { value: curr, .. } => Ok(curr == value),
+            Command::Remove { .. } => Err(KvError::UnexpectedCommandType),
+        }
+    }
+
+    fn log_bytes(&self) -> u64 {
+        self.sealed_bytes + self.writer.index
+    }
+
+    /// Seals the active log and continues in a new one, unless the active log is empty.
+    fn start_generation(&mut self) -> Result<()> {
+        if self.writer.index == log_header_len() {
+            return Ok(());
+        }
+        self.writer.flush()?;
+        let writer = new_log_file(&self.log_dir, self.curr_version + 1)?;
+        self.sealed_bytes += self.writer.index;
+        self.writer = writer;
+        self.curr_version += 1;
+        self.generations += 1;
+        Ok(())
+    }
+
+    fn maybe_compact(&mut self) -> Result<()> {
+        if !self.compaction_due() {
+            return Ok(());
+        }
+        match &mut self.maintenance {
+            Some(maintenance) => {
+                maintenance.schedule_compaction();
+                Ok(())
+            }
+            None => self.compact(),
+        }
+    }
+
+    fn compaction_due(&self) -> bool {
+        if !self.unknown_logs.is_empty() {
+            return false;
+        }
+        if let (Some(cooldown), Some(last)) = (self.compaction_cooldown, self.last_compaction) {
+            if last.elapsed() < cooldown {
+                return false;
+            }
+        }
+        let too_many_generations = self
+            .max_generations
+            .map_or(false, |max| self.generations > max);
+        let too_much_garbage = match self.compaction_ratio {
+            Some(ratio) => self.uncompacted as f64 > ratio * self.log_bytes() as f64,
+            None => self.uncompacted > COMPACTION_THRESHOLD,
+        };
+        too_many_generations || too_much_garbage
+    }
+
+    fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        keys.into_iter()
+            .map(|key| {
+                if self.index.contains_key(&key) {
+                    self.remove(key)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            })
+            .collect()
+    }
+
+    /// Removes every key, returning the entries that were live.
+    fn drain(&mut self) -> Result<Vec<(String, String)>> {
+        let now = now_millis();
+        let mut drained = Vec::with_capacity(self.index.len());
+        for entry in self.index.iter() {
+            if entry.value().expired(now) {
+                continue;
+            }
+            if let Command::Set { key, value, .. } =
+                self.reader.read_command(entry.key(), *entry.value())?
+            {
+                drained.push((key, value));
+            }
+        }
+        self.remove_all()?;
+        // The keys are gone already, failing now would lose their values
+        if let Err(e) = self.maybe_compact() {
+            error!("Compaction after a drain failed: {}", e);
+        }
+        Ok(drained)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.remove_all()?;
+        self.maybe_compact()
+    }
+
+    /// Removes every key with a single write, so either all of them or none are removed.
+    fn remove_all(&mut self) -> Result<()> {
+        let cmds: Vec<_> = self
+            .index
+            .iter()
+            .map(|entry| Command::remove(entry.key().clone()))
+            .collect();
+        let ranges = self.write_commands(&cmds)?;
+        for (cmd, range) in cmds.into_iter().zip(ranges) {
+            if let Command::Remove { key } = cmd {
+                self.record_write(&key);
+                self.index_remove(key, range.end - range.start);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.index.contains_key(&key) {
+            self.record_write(&key);
+            let cmd = Command::remove(key);
+            let pos = self.writer.index;
+            self.write_command(&cmd)?;
+            if let Command::Remove {key} = cmd {
+                self.index_remove(key, self.writer.index - pos);
+            }
+            self.maybe_compact()
+        } else {
+            Err(KvError::KeyNotFound)
+        }
+    }
+    /// Copies the live entries into log `compact_version`, returning their new positions
+    /// and the length of the tombstones carried over.
+    fn write_compacted(
+        &mut self,
+        compact_version: u64,
+    ) -> Result<(Vec<(String, CommandIndex)>, u64)> {
+        let mut compact_writer = new_log_file(&self.log_dir, compact_version)?;
+        let records_start = compact_writer.index;
+
+        let now = now_millis();
+        let mut live: Vec<_> = self
+            .index
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .filter(|(_, cmd_pos)| !cmd_pos.expired(now))
+            .collect();
+        if self.compaction_order == CompactionOrder::ByGeneration {
+            live.sort_unstable_by_key(|(_, cmd_pos)| (cmd_pos.version, cmd_pos.start));
+        }
+        // Records are copied as they are, as every format encodes them the same
+        let mut new_pos = records_start;
+        let mut moved = Vec::with_capacity(live.len());
+        let mut push = |key: String, old_pos: CommandIndex, len: u64| {
+            let cmd_pos: CommandIndex =
+                (compact_version, new_pos..new_pos + len, old_pos.value_len).into();
+            let cmd_pos = CommandIndex {
+                expire_at: old_pos.expire_at,
+                written_at: old_pos.written_at,
+                format_tag: old_pos.format_tag,
+                ..cmd_pos
+            };
+            moved.push((key, cmd_pos));
+            new_pos += len;
+        };
+        if self.compaction_threads > 1 {
+            // Reads may run in any order, but the records have to be written in order
+            // as their new positions depend on it.
+            for batch in live.chunks(COMPACTION_BATCH) {
+                let records = self.read_records(batch)?;
+                for ((key, old_pos), record) in batch.iter().zip(records) {
+                    compact_writer.write_all(&record)?;
+                    push(key.clone(), *old_pos, record.len() as u64);
+                }
+            }
+        } else {
+            for (key, old_pos) in live {
+                let len = self.reader.read_and(old_pos, |mut entry_reader| {
+                    Ok(io::copy(&mut entry_reader, &mut compact_writer)?)
+                })?;
+                push(key, old_pos, len);
+            }
+        }
+        // Tombstones still in their grace period are carried over so they survive a reopen.
+        if let Some(retention) = self.tombstone_retention {
+            for entry in self.tombstones.iter() {
+                if entry.value().elapsed() >= retention {
+                    entry.remove();
+                    self.evicted.push(entry.key().clone());
+                } else {
+                    serde_json::to_writer(&mut compact_writer, &Command::remove(entry.key().clone()))?;
+                }
+            }
+        }
+        match self.sync_policy {
+            SyncPolicy::Flush => compact_writer.flush()?,
+            SyncPolicy::Always => compact_writer.sync_all()?,
+        }
+        Ok((moved, compact_writer.index - new_pos))
+    }
+
+    /// Reads the raw records of `batch` on `compaction_threads` threads, in the order of `batch`.
+    fn read_records(&self, batch: &[(String, CommandIndex)]) -> Result<Vec<Vec<u8>>> {
+        let chunk_len = (batch.len() + self.compaction_threads - 1) / self.compaction_threads;
+        crossbeam::thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .chunks(chunk_len.max(1))
+                .map(|chunk| {
+                    let reader = self.reader.clone();
+                    scope.spawn(move |_| {
+                        chunk
+                            .iter()
+                            .map(|(_, cmd_pos)| {
+                                reader.read_and(*cmd_pos, |mut entry_reader| {
+                                    let mut record = Vec::with_capacity(cmd_pos.len as usize);
+                                    entry_reader.read_to_end(&mut record)?;
+                                    Ok(record)
+                                })
+                            })
+                            .collect::<Result<Vec<_>>>()
+                    })
+                })
+                .collect();
+            let mut records = Vec::with_capacity(batch.len());
+            for handle in handles {
+                let chunk = handle
+                    .join()
+                    .map_err(|_| KvError::StringError("compaction reader panicked".to_owned()))??;
+                records.extend(chunk);
+            }
+            Ok(records)
+        })
+        .map_err(|_| KvError::StringError("compaction reader panicked".to_owned()))?
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        if let Some(gen) = self.unknown_logs.iter().next() {
+            return Err(KvError::StringError(format!(
+                "log {} holds records of commands this version doesn't know, \
+                 compacting would lose them",
+                gen
+            )));
+        }
+        let started = Instant::now();
+        self.last_compaction = Some(started);
+        let bytes_before = self.log_bytes();
+        let compact_version = self.curr_version + 1;
+        self.curr_version += 2;
+
+        self.writer = new_log_file(&self.log_dir, self.curr_version)?;
+
+        let (moved, tombstone_bytes) = match self.write_compacted(compact_version) {
+            Ok(res) => res,
+            Err(e) => {
+                // Don't leave a partial log around to be loaded by the next open
+                let file_path = log_path(&self.log_dir, compact_version);
+                if let Err(e) = fs::remove_file(&file_path) {
+                    error!("{:?} cannot be deleted: {}", file_path, e);
+                }
+                return Err(e);
+            }
+        };
+        // Readers may only see the new positions once the compacted log is flushed,
+        // and they have to see them before any stale log gets deleted.
+        // Entries that weren't moved have expired and go away with their logs.
+        let mut moved: BTreeMap<_, _> = moved.into_iter().collect();
+        for entry in self.index.iter() {
+            match moved.remove(entry.key()) {
+                Some(cmd_pos) => {
+                    self.index.insert(entry.key().clone(), cmd_pos);
+                }
+                None => {
+                    entry.remove();
+                    self.evicted.push(entry.key().clone());
+                }
+            }
+        }
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+        self.reader.curr_version.store(compact_version, Ordering::SeqCst);
+        self.reader.remove_timeout_log();
+
+        let stale_gens = get_log_list(&self.log_dir)?
+            .into_iter()
+            .filter(|&gen| gen < compact_version);
+
+        for stale_gen in stale_gens {
+            let file_path = log_path(&self.log_dir, stale_gen);
+            if let Err(e) = fs::remove_file(&file_path) {
+                error!("{:?} cannot be deleted: {}", file_path, e);
+            }
+        }
+        // Deleted logs only free their space once no reader has them open anymore
+        if let Some(keep) = self.max_retained_generations {
+            self.reader.registry.close_stale(compact_version, keep);
+        }
+        // The carried over tombstones can go in a later compaction, as `load` counts them
+        self.uncompacted = tombstone_bytes;
+        self.sealed_bytes = fs::metadata(log_path(&self.log_dir, compact_version))?.len();
+        self.generations = 2;
+        self.compaction_counters.runs += 1;
+        self.compaction_counters.time += started.elapsed();
+        self.compaction_counters.reclaimed_bytes += bytes_before.saturating_sub(self.log_bytes());
+        self.compaction_counters.written_bytes += self.sealed_bytes;
+
+        // A stale snapshot only costs a full replay, the compaction itself went through
+        if let Err(e) = self.snapshot() {
+            error!("Failed to write index snapshot: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Tells the user how to open a store on a read-only filesystem instead of failing
+/// with a bare io error.
+fn read_only_error(e: KvError, dir: &Path) -> KvError {
+    match e {
+        KvError::Io(ref io_err)
+            if io_err.kind() == io::ErrorKind::PermissionDenied
+                || io_err.raw_os_error() == Some(EROFS) =>
+        {
+            KvError::ReadOnlyFilesystem(dir.to_path_buf())
+        }
+        e => e,
+    }
+}
+
+/// Writes tombstones for expired keys every `interval` until the store is dropped, so keys
+/// nobody reads again don't stay on disk until the next compaction.
+fn spawn_reaper(
+    writer: Weak<Mutex<KvStoreWriter>>,
+    tasks: Arc<BackgroundTasks>,
+    index: Arc<SkipMap<String, CommandIndex>>,
+    on_evict: Option<EvictHook>,
+    interval: Duration,
+) -> Result<()> {
+    thread::Builder::new()
+        .name("kv-reaper".to_owned())
+        .spawn(move || {
+            // The index is gone through in batches, `cursor` is the last key checked
+            let mut cursor = None;
+            loop {
+                if cursor.is_none() {
+                    thread::sleep(interval);
+                }
+                let start = cursor.take().map_or(Bound::Unbounded, Bound::Excluded);
+                let now = now_millis();
+                let mut checked = 0;
+                let mut expired = Vec::new();
+                for entry in index.range((start, Bound::Unbounded)).take(REAP_BATCH) {
+                    checked += 1;
+                    cursor = Some(entry.key().clone());
+                    if entry.value().expired(now) {
+                        expired.push((entry.key().clone(), *entry.value()));
+                    }
+                }
+                if checked < REAP_BATCH {
+                    cursor = None;
+                }
+
+                let task = tasks.start();
+                let writer = match writer.upgrade() {
+                    Some(writer) => writer,
+                    None => return,
+                };
+                if expired.is_empty() {
+                    continue;
+                }
+                let evicted = {
+                    let mut writer = writer.lock().unwrap();
+                    for (key, cmd_pos) in expired {
+                        if let Err(e) = writer.expire(key, cmd_pos) {
+                            error!("Failed to remove expired keys: {}", e);
+                            break;
+                        }
+                    }
+                    mem::replace(&mut writer.evicted, Vec::new())
+                };
+                drop(writer);
+                drop(task);
+                if let Some(on_evict) = &on_evict {
+                    for key in evicted {
+                        (on_evict.0)(&key);
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Data directories of the stores open in this process.
+static OPEN_DIRS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Lock on a data directory against other processes and other stores in this one.
+struct DirLock {
+    path: PathBuf,
+    _file: Option<File>,
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        OPEN_DIRS.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// Takes the lock on the data directory, held until the store is dropped.
+fn lock_dir(meta_dir: &Path) -> Result<DirLock> {
+    // The file lock alone would fail too, but not on every filesystem and less clearly
+    let path = meta_dir.canonicalize()?;
+    if !OPEN_DIRS.lock().unwrap().insert(path.clone()) {
+        return Err(KvError::AlreadyOpen(path));
+    }
+    let mut lock = DirLock { path, _file: None };
+
+    let lock_path = meta_dir.join(LOCK_FILE);
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| read_only_error(e.into(), meta_dir))?;
+    match file.try_lock_exclusive() {
+        Ok(()) => {
+            lock._file = Some(file);
+            Ok(lock)
+        }
+        Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+            Err(KvError::AlreadyLocked(lock_path))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithIndex<File>> {
+    let path = log_path(&path, gen);
+    let mut writer = BufWriterWithIndex::new(
+        OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .append(true)
+            .open(&path)?,
+    )?;
+    serde_json::to_writer(&mut writer, &LogHeader::Format(LOG_FORMAT))?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// Opens log `gen` for reading, see `read_log_header`.
+fn open_log(path: &Path, gen: u64) -> Result<BufReaderWithIndex<File>> {
+    read_log_header(BufReaderWithIndex::new(File::open(log_path(path, gen))?)?, gen)
+}
+
+/// Reads the format of log `gen` off its header, failing for formats newer than this
+/// build knows.
+fn read_log_header(
+    mut reader: BufReaderWithIndex<File>,
+    gen: u64,
+) -> Result<BufReaderWithIndex<File>> {
+    let mut prefix = [0; LOG_HEADER_PREFIX.len()];
+    let has_header = match reader.read_exact(&mut prefix) {
+        Ok(()) => prefix == LOG_HEADER_PREFIX,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e.into()),
+    };
+    reader.seek(SeekFrom::Start(0))?;
+    if !has_header {
+        reader.format = LEGACY_LOG_FORMAT;
+        return Ok(reader);
+    }
+    let mut stream = Deserializer::from_reader(&mut reader).into_iter::<LogHeader>();
+    let LogHeader::Format(format) = match stream.next() {
+        Some(header) => header?,
+        None => unreachable!("a header was found"),
+    };
+    let records_start = stream.byte_offset() as u64;
+    if format > LOG_FORMAT {
+        return Err(KvError::UnsupportedFormat { version: gen, format });
+    }
+    if format < LEGACY_LOG_FORMAT {
+        return Err(KvError::Corruption(format!(
+            "log {} has invalid format {}",
+            gen, format
+        )));
+    }
+    reader.format = format;
+    reader.records_start = records_start;
+    reader.seek(SeekFrom::Start(records_start))?;
+    Ok(reader)
+}
+
+/// Fails if the store in `meta_dir` keeps its logs in another directory than `log_dir`.
+/// Records `log_dir` if it isn't `default_log_dir` and `record` is set.
+fn check_log_dir(
+    meta_dir: &Path,
+    log_dir: &Path,
+    default_log_dir: &Path,
+    record: bool,
+) -> Result<()> {
+    let log_dir = log_dir.canonicalize()?;
+    let default_log_dir = default_log_dir.canonicalize().unwrap_or_default();
+    let mismatch = |recorded: &Path| {
+        KvError::StringError(format!(
+            "{:?} keeps its logs in {:?}, not {:?}",
+            meta_dir, recorded, log_dir
+        ))
+    };
+    let file = meta_dir.join(LOG_DIR_FILE);
+    match fs::read_to_string(&file) {
+        Ok(recorded) if Path::new(&recorded) == log_dir => Ok(()),
+        Ok(recorded) => Err(mismatch(Path::new(&recorded))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            if log_dir == default_log_dir {
+                return Ok(());
+            }
+            // Logs left in the data directory by opens without a separate log directory
+            if default_log_dir.is_dir() && !get_log_list(&default_log_dir)?.is_empty() {
+                return Err(mismatch(&default_log_dir));
+            }
+            if record {
+                let path = log_dir.to_str().ok_or_else(|| {
+                    KvError::StringError(format!("{:?} is not valid UTF-8", log_dir))
+                })?;
+                // Written aside and renamed, so a crash never leaves a partial path behind
+                let tmp = meta_dir.join(format!("{}.tmp", LOG_DIR_FILE));
+                fs::write(&tmp, path)?;
+                fs::rename(tmp, file)?;
+            }
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Notices logs created or deleted by the process writing a store opened read-only.
+struct LogWatch {
+    log_dir: Arc<PathBuf>,
+    /// Modification time of the log directory when the index was last loaded.
+    mtime: Mutex<SystemTime>,
+}
+
+#[derive(Clone)]
+pub struct KvStore {
+    path: Arc<PathBuf>,
+
+    index: Arc<SkipMap<String, CommandIndex>>,
+
+    reader: KvStoreReader,
+
+    /// `None` if the store was opened read-only.
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+
+    cache: Option<Arc<Mutex<ValueCache>>>,
+
+    tombstones: Arc<SkipMap<String, Instant>>,
+
+    on_evict: Option<EvictHook>,
+
+    key_locks: Arc<KeyLocks>,
+
+    /// Read keys whose record is a removal as absent instead of failing.
+    lenient_reads: bool,
+
+    /// Rewrite values read from older logs into the active one.
+    read_repair: bool,
+
+    value_index: Option<Arc<ValuePrefixIndex>>,
+
+    /// Counts of reads and writes per key, if enabled.
+    access: Option<Arc<AccessTracker>>,
+
+    /// `Some` if the store was opened read-only.
+    log_watch: Option<Arc<LogWatch>>,
+
+    /// Shared by the clones of the store only, unlike the writer, which background tasks
+    /// hold for a moment too.
+    handles: Arc<()>,
+    tasks: Arc<BackgroundTasks>,
+}
+
+/// Error of `KvStore::close`, giving the store back if it wasn't closed.
+pub struct CloseError {
+    error: KvError,
+    store: Option<Box<KvStore>>,
+}
+
+impl CloseError {
+    pub fn error(&self) -> &KvError {
+        &self.error
+    }
+
+    /// The store, if it is still open because other clones were around.
+    pub fn into_store(self) -> Option<KvStore> {
+        self.store.map(|store| *store)
+    }
+}
+
+impl fmt::Debug for CloseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CloseError").field("error", &self.error).finish()
+    }
+}
+
+impl fmt::Display for CloseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl Error for CloseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+impl From<CloseError> for KvError {
+    fn from(err: CloseError) -> KvError {
+        err.error
+    }
+}
+
+impl KvStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = Arc::new(path.into());
+        let log_dir = match &options.log_dir {
+            Some(log_dir) => Arc::new(log_dir.clone()),
+            None => Arc::new(options.layout.log_dir(&path)),
+        };
+        let meta_dir = options.layout.meta_dir(&path);
+        let default_log_dir = options.layout.log_dir(&path);
+        let mut dir_locks = Vec::new();
+        if options.read_only {
+            if !log_dir.is_dir() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{:?} is not a directory", log_dir),
+                )
+                .into());
+            }
+            expect_engine(&meta_dir, EngineKind::KvStore)?;
+            check_log_dir(&meta_dir, &log_dir, &default_log_dir, false)?;
+        } else {
+            fs::create_dir_all(&*log_dir)?;
+            fs::create_dir_all(&meta_dir)?;
+            if options.lock {
+                dir_locks.push(lock_dir(&meta_dir)?);
+                if log_dir.canonicalize()? != meta_dir.canonicalize()? {
+                    dir_locks.push(lock_dir(&log_dir)?);
+                }
+            }
+            check_engine(&meta_dir, EngineKind::KvStore)
+                .map_err(|e| read_only_error(e, &meta_dir))?;
+            check_log_dir(&meta_dir, &log_dir, &default_log_dir, true)?;
+            verify_manifest(&meta_dir, &log_dir, options.manifest)?;
+        }
+
+        let mut readers = BTreeMap::new();
+        let index = Arc::new(SkipMap::new());
+        let tombstones = Arc::new(SkipMap::new());
+        let retained_tombstones = options.tombstone_retention.map(|_| &*tombstones);
+
+        let gen_list = get_log_list(&log_dir)?;
+        let mut uncompacted = 0;
+        let mut unknown_logs = BTreeSet::new();
+
+        // Start from the snapshot of the index if it is still valid for the logs
+        let mut replay_from = BTreeMap::new();
+        if options.index_snapshot {
+            if let Some(snapshot) = read_snapshot(&meta_dir)? {
+                match snapshot.replay_from(&log_dir, &gen_list) {
+                    Some(from) => {
+                        replay_from = from;
+                        uncompacted = snapshot.uncompacted;
+                        unknown_logs.extend(snapshot.unknown_logs);
+                        for (key, cmd_pos) in snapshot.entries {
+                            index.insert(key, cmd_pos);
+                        }
+                        if let Some(tombstones) = retained_tombstones {
+                            for key in snapshot.tombstones {
+                                tombstones.insert(key, Instant::now());
+                            }
+                        }
+                    }
+                    None => warn!("Index snapshot is stale, replaying all logs"),
+                }
+            }
+        }
+
+        let progress = options.on_replay_progress.as_ref().map(|hook| &hook.0);
+        let mut total_bytes = 0;
+        let mut replay = Vec::with_capacity(gen_list.len());
+        for &gen in &gen_list {
+            let reader = open_log(&log_dir, gen)?;
+            let from = replay_from.get(&gen).cloned().unwrap_or(reader.records_start);
+            if progress.is_some() {
+                total_bytes += fs::metadata(log_path(&log_dir, gen))?.len().saturating_sub(from);
+            }
+            replay.push((gen, from, reader));
+        }
+        let mut bytes_read = 0;
+        for (gen, from, mut reader) in replay {
+            let report = progress.map(|hook| move |pos| hook(gen, bytes_read + pos, total_bytes));
+            let report = report.as_ref().map(|report| report as &dyn Fn(u64));
+            let (bytes, unknown) =
+                load(gen, from, &mut reader, &*index, retained_tombstones, report)?;
+            uncompacted += bytes;
+            if unknown {
+                unknown_logs.insert(gen);
+            }
+            if let Some(hook) = progress {
+                bytes_read += fs::metadata(log_path(&log_dir, gen))?.len().saturating_sub(from);
+                hook(gen, bytes_read, total_bytes);
+            }
+            readers.insert(gen, reader);
+        }
+
+        if let Some(&last) = gen_list.last() {
+            let last_log = log_path(&log_dir, last);
+            if fs::metadata(&last_log)?.len() == readers[&last].records_start {
+                warn!(
+                    "{:?} is empty, the store wasn't closed cleanly and writes in flight are lost",
+                    last_log
+                );
+            }
+        }
+        let current_gen = next_generation(&log_dir)?;
+        let mut sealed_bytes = 0;
+        for &gen in &gen_list {
+            sealed_bytes += fs::metadata(log_path(&log_dir, gen))?.len();
+        }
+        let safe_point = Arc::new(AtomicU64::new(0));
+
+        let reader = KvStoreReader::new(Arc::clone(&log_dir), safe_point, readers);
+        let value_index = match options.value_prefix_index {
+            Some(len) => Some(Arc::new(build_value_index(len, &index, &reader)?)),
+            None => None,
+        };
+
+        let cache = if options.value_cache_bytes > 0 {
+            Some(Arc::new(Mutex::new(ValueCache::new(options.value_cache_bytes))))
+        } else {
+            None
+        };
+
+        let access = options.track_access.map(|sample| Arc::new(AccessTracker::new(sample)));
+
+        if options.read_only {
+            let log_watch = LogWatch {
+                mtime: Mutex::new(fs::metadata(&*log_dir)?.modified()?),
+                log_dir,
+            };
+            return Ok(KvStore {
+                path,
+                reader,
+                index,
+                writer: None,
+                cache,
+                tombstones,
+                on_evict: options.on_evict,
+                key_locks: Arc::new(KeyLocks::new()),
+                lenient_reads: options.lenient_reads,
+                read_repair: false,
+                value_index,
+                access,
+                log_watch: Some(Arc::new(log_watch)),
+                handles: Arc::default(),
+            tasks: Arc::default(),
+            });
+        }
+
+        let writer =
+            new_log_file(&log_dir, current_gen).map_err(|e| read_only_error(e, &log_dir))?;
+        let writer = KvStoreWriter {
+            reader: reader.clone(),
+            writer,
+            scratch: Vec::new(),
+            curr_version: current_gen,
+            uncompacted,
+            unknown_logs,
+            generations: gen_list.len() + 1,
+            max_generations: options.max_generations,
+            max_value_bytes: options.max_value_bytes,
+            format_tags: options.format_tags,
+            compaction_ratio: options.compaction_ratio,
+            compaction_cooldown: options.compaction_cooldown,
+            last_compaction: None,
+            max_retained_generations: options.max_retained_generations,
+            compaction_counters: CompactionCounters::default(),
+            written_bytes: 0,
+            sealed_bytes,
+            log_dir,
+            index: Arc::clone(&index),
+            cache: cache.clone(),
+            tombstones: Arc::clone(&tombstones),
+            tombstone_retention: options.tombstone_retention,
+            dedup_sets: options.dedup_sets,
+            sync_policy: options.sync_policy,
+            compaction_threads: options.compaction_threads,
+            compaction_order: options.compaction_order,
+            evicted: Vec::new(),
+            manifest_dir: if options.manifest { Some(meta_dir.clone()) } else { None },
+            snapshot_dir: if options.index_snapshot { Some(meta_dir) } else { None },
+            closed: false,
+            value_index: value_index.clone(),
+            access: access.clone(),
+            maintenance: None,
+            _dir_locks: dir_locks,
+        };
+
+        let store = KvStore {
+            path,
+            reader,
+            index,
+            writer: Some(Arc::new(Mutex::new(writer))),
+            cache,
+            tombstones,
+            on_evict: options.on_evict,
+            key_locks: Arc::new(KeyLocks::new()),
+            lenient_reads: options.lenient_reads,
+            read_repair: options.read_repair,
+            value_index,
+            access,
+            log_watch: None,
+            handles: Arc::default(),
+            tasks: Arc::default(),
+        };
+        if let Some(writer) = &store.writer {
+            if options.maintenance_threads > 0 {
+                let pool = SharedQueueThreadPool::with_name_prefix(
+                    "kv-maintenance",
+                    options.maintenance_threads as i32,
+                )?;
+                writer.lock().unwrap().maintenance = Some(Maintenance {
+                    pool,
+                    writer: Arc::downgrade(writer),
+                    tasks: Arc::clone(&store.tasks),
+                    compaction_scheduled: false,
+                });
+            }
+        }
+        store.with_writer(|writer| writer.maybe_compact())?;
+        if let (Some(interval), Some(writer)) = (options.reap_interval, &store.writer) {
+            spawn_reaper(
+                Arc::downgrade(writer),
+                Arc::clone(&store.tasks),
+                Arc::clone(&store.index),
+                store.on_evict.clone(),
+                interval,
+            )?;
+        }
+        Ok(store)
+    }
+
+    fn writer(&self) -> Result<&Mutex<KvStoreWriter>> {
+        self.writer.as_ref().map(|writer| &**writer).ok_or(KvError::ReadOnly)
+    }
+
+    /// Runs `f` under the writer lock, then reports the keys it evicted to the
+    /// `on_evict` hook once the lock is released, so the hook may use the store.
+    fn with_writer<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut KvStoreWriter) -> Result<R>,
+    {
+        self.with_key_locks(Vec::new(), f)
+    }
+
+    /// Like `with_writer`, also releasing the key locks `guards` before calling the hook.
+    fn with_key_locks<F, R>(&self, guards: Vec<MutexGuard<()>>, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut KvStoreWriter) -> Result<R>,
+    {
+        let (res, evicted) = {
+            let mut writer = self.writer()?.lock().unwrap();
+            let res = f(&mut writer);
+            (res, mem::replace(&mut writer.evicted, Vec::new()))
+        };
+        drop(guards);
+        if let Some(on_evict) = &self.on_evict {
+            for key in evicted {
+                (on_evict.0)(&key);
+            }
+        }
+        res
+    }
+
+    /// Sets `key` to `value`, expiring it after `ttl`.
+    ///
+    /// Expired keys read as absent and are removed on their next access or compaction.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expire_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        let guard = self.key_locks.lock(&key);
+        self.with_key_locks(vec![guard], |writer| {
+            writer.set(key, value, Some(expire_at), None)
+        })
+    }
+
+    /// The data directory the store was opened in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Like `get`, but tells a removed key apart from one that never existed.
+    ///
+    /// Removed keys are only reported as `KeyState::Tombstoned` when the store was
+    /// opened with a tombstone retention.
+    pub fn get_state(&self, key: String) -> Result<KeyState> {
+        if let Some(value) = self.get(key.clone())? {
+            Ok(KeyState::Present(value))
+        } else if self.tombstones.contains_key(&key) {
+            Ok(KeyState::Tombstoned)
+        } else {
+            Ok(KeyState::Absent)
+        }
+    }
+
+    /// Sets `key` to `value`, tagging the value with `format_tag` to tell tools how it is
+    /// encoded. The tag is stored next to the value and doesn't change it.
+    pub fn set_with_format(&self, key: String, value: String, format_tag: u8) -> Result<()> {
+        let guard = self.key_locks.lock(&key);
+        self.with_key_locks(vec![guard], |writer| {
+            writer.set(key, value, None, Some(format_tag))
+        })
+    }
+
+    /// Format tag of the value of `key`, 0 for values set without one.
+    pub fn value_format(&self, key: String) -> Result<Option<u8>> {
+        Ok(match self.index.get(&key) {
+            Some(entry) if !entry.value().expired(now_millis()) => Some(entry.value().format_tag),
+            _ => None,
+        })
+    }
+
+    /// Like `get`, but also returns the version of the value for `set_if_version`.
+    pub fn get_versioned(&self, key: String) -> Result<Option<(String, ValueVersion)>> {
+        loop {
+            let before = self.index.get(&key).map(|entry| *entry.value());
+            let value = self.get(key.clone())?;
+            let after = self.index.get(&key).map(|entry| *entry.value());
+            // Positions are never reused, so an unchanged one means the value belongs to it
+            if before == after {
+                return Ok(match (value, after) {
+                    (Some(value), Some(cmd_pos)) => Some((value, cmd_pos.value_version())),
+                    _ => None,
+                });
+            }
+        }
+    }
+
+    /// Sets `key` to `value` only if it still holds the value of `version`, returning
+    /// whether it did.
+    pub fn set_if_version(
+        &self,
+        key: String,
+        value: String,
+        version: ValueVersion,
+    ) -> Result<bool> {
+        let guard = self.key_locks.lock(&key);
+        self.with_key_locks(vec![guard], |writer| {
+            let now = now_millis();
+            match writer.index.get(&key) {
+                Some(entry)
+                    if !entry.value().expired(now) && entry.value().value_version() == version => {}
+                _ => return Ok(false),
+            }
+            writer.set(key, value, None, None)?;
+            Ok(true)
+        })
+    }
+
+    /// Returns the value of `key`, first setting it to `f()` if it is absent.
+    ///
+    /// `f` runs holding only the lock of `key`, so other writes aren't kept waiting, and
+    /// concurrent calls for the same key wait for it and return its value instead of
+    /// calling their own. `f` must not write to the store, which may deadlock.
+    pub fn get_or_insert_with<F: FnOnce() -> String>(&self, key: String, f: F) -> Result<String> {
+        if let Some(value) = self.get(key.clone())? {
+            return Ok(value);
+        }
+        self.writer()?;
+        let guard = self.key_locks.lock(&key);
+        // Inserted by another call while we waited for the lock
+        if let Some(value) = self.get_locked(key.clone())? {
+            return Ok(value);
+        }
+        let value = f();
+        self.with_key_locks(vec![guard], |writer| {
+            writer.expire_key(&key)?;
+            writer.set(key, value.clone(), None, None)
+        })?;
+        Ok(value)
+    }
+
+    /// Time `key` has left before it expires, `None` if it is absent or was set without a TTL.
+    pub fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        let now = now_millis();
+        Ok(match self.index.get(&key) {
+            Some(entry) if !entry.value().expired(now) => entry
+                .value()
+                .expire_at
+                .map(|expire_at| Duration::from_millis(expire_at - now)),
+            _ => None,
+        })
+    }
+
+    /// When `key` was last set, `None` if it is absent or was written by an older version.
+    pub fn last_modified(&self, key: String) -> Result<Option<SystemTime>> {
+        Ok(match self.index.get(&key) {
+            Some(entry) if !entry.value().expired(now_millis()) => entry
+                .value()
+                .written_at
+                .map(|millis| UNIX_EPOCH + Duration::from_millis(millis)),
+            _ => None,
+        })
+    }
+
+    /// Live keys in order.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        self.refresh(false)?;
+        let now = now_millis();
+        Ok(self
+            .index
+            .iter()
+            .filter(|entry| !entry.value().expired(now))
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+
+    /// Live pairs whose key starts with `prefix`, in key order.
+    pub fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let keys: Vec<_> = self
+            .index
+            .range(prefix.clone()..)
+            .map(|entry| entry.key().clone())
+            .take_while(|key| key.starts_with(&prefix))
+            .collect();
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Calls `f` with each live pair whose key is in `range`, in key order, reading the
+    /// values one at a time instead of collecting them.
+    ///
+    /// An error returned by `f` stops the iteration and is returned, which is also the way
+    /// to stop early. Keys written meanwhile may or may not be seen.
+    pub fn for_each_in_range<R, F>(&self, range: R, mut f: F) -> Result<()>
+    where
+        R: RangeBounds<String>,
+        F: FnMut(&str, &str) -> Result<()>,
+    {
+        for entry in self.index.range(range) {
+            if let (Some(value), _) = self.read_value(entry.key().clone(), true, true)? {
+                f(entry.key(), &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of keys in the store, including expired ones not removed yet.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Removes every key.
+    pub fn clear(&self) -> Result<()> {
+        self.with_key_locks(self.key_locks.lock_all(), |writer| writer.clear())
+    }
+
+    /// Removes every key and returns the live entries.
+    ///
+    /// All writes wait for the drain, so every entry set before it is either returned
+    /// or still in the store afterwards. A concurrent `get` may see a key removed while
+    /// the drain is going on: the removals are logged with a single write, but the keys
+    /// then leave the index one by one. If the drain fails, nothing was removed.
+    pub fn drain(&self) -> Result<Vec<(String, String)>> {
+        self.with_key_locks(self.key_locks.lock_all(), |writer| writer.drain())
+    }
+
+    /// Rewrites the live entries into a new log and deletes the stale ones.
+    pub fn compact(&self) -> Result<()> {
+        self.with_writer(|writer| writer.compact())
+    }
+
+    /// Writes a copy of the store as of now into `dest`, which `KvStore::open` can load.
+    ///
+    /// Sealed logs are hard-linked where possible. Writes are only blocked while the
+    /// logs are linked or opened, logs that can't be linked and the active log up to the
+    /// snapshot point are copied afterwards.
+    pub fn backup(&self, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest)?;
+        if !get_log_list(dest)?.is_empty() {
+            return Err(KvError::StringError(format!(
+                "{:?} already contains logs",
+                dest
+            )));
+        }
+        let mut unlinked = Vec::new();
+        let (active_version, active_len, mut active) = {
+            let mut writer = self.writer()?.lock().unwrap();
+            writer.writer.flush()?;
+            for gen in get_log_list(&writer.log_dir)? {
+                if gen >= writer.curr_version {
+                    continue;
+                }
+                let src = log_path(&writer.log_dir, gen);
+                if fs::hard_link(&src, log_path(dest, gen)).is_err() {
+                    // e.g. `dest` is on another filesystem, copy it once the lock is released
+                    unlinked.push((gen, File::open(&src)?));
+                }
+            }
+            // The open handles keep the logs readable even if a compaction removes them
+            let active = File::open(log_path(&writer.log_dir, writer.curr_version))?;
+            (writer.curr_version, writer.writer.index, active)
+        };
+        for (gen, mut src) in unlinked {
+            let mut copy = File::create(log_path(dest, gen))?;
+            io::copy(&mut src, &mut copy)?;
+            copy.sync_all()?;
+        }
+        let mut snapshot = File::create(log_path(dest, active_version))?;
+        io::copy(&mut (&mut active).take(active_len), &mut snapshot)?;
+        snapshot.sync_all()?;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = match &self.writer {
+            Some(writer) => {
+                let writer = writer.lock().unwrap();
+                Stats {
+                    generations: writer.generations as u64,
+                    uncompacted: writer.uncompacted,
+                    log_bytes: writer.log_bytes(),
+                    compactions: writer.compaction_counters.runs,
+                    compaction_millis: writer.compaction_counters.time.as_millis() as u64,
+                    compaction_reclaimed_bytes: writer.compaction_counters.reclaimed_bytes,
+                    bytes_written: writer.written_bytes,
+                    compaction_bytes_written: writer.compaction_counters.written_bytes,
+                    ..Stats::default()
+                }
+            }
+            None => Stats::default(),
+        };
+        stats.cache_hits = self.cache_hits();
+        stats.cache_bytes = self.cache.as_ref().map_or(0, |cache| cache.lock().unwrap().size());
+        for entry in self.index.iter() {
+            stats.keys += 1;
+            stats.record_value_size(entry.value().value_len);
+        }
+        stats
+    }
+
+    /// Like `get`, also reporting the I/O the read took.
+    pub fn get_with_stats(&self, key: String) -> Result<(Option<String>, ReadStats)> {
+        self.read_value(key, true, true)
+    }
+
+    /// Like `get`, but always reads the value from its log, neither using nor filling
+    /// the value cache.
+    pub fn get_uncached(&self, key: String) -> Result<Option<String>> {
+        self.read_value(key, false, true).map(|(value, _)| value)
+    }
+
+    /// Like `get`, for callers holding the lock of `key`. An expired value reads as absent
+    /// but stays in place, as expiring it would run the `on_evict` hook under that lock, so
+    /// the caller's write has to expire it with `KvStoreWriter::expire_key`.
+    fn get_locked(&self, key: String) -> Result<Option<String>> {
+        self.read_value(key, true, false).map(|(value, _)| value)
+    }
+
+    /// Rewrites `value`, read from `cmd_pos`, into the active log if the writer is free.
+    fn repair(&self, key: &str, value: &str, cmd_pos: CommandIndex) {
+        let mut writer = match self.writer.as_ref().map(|writer| writer.try_lock()) {
+            Some(Ok(writer)) => writer,
+            _ => return,
+        };
+        if let Err(e) = writer.repair(key, value, cmd_pos) {
+            warn!("Failed to rewrite `{}` into the active log: {}", key, e);
+        }
+    }
+
+    /// Reloads the index of a read-only store if the process writing it created or deleted
+    /// logs since the last load, or anyway if `force`.
+    ///
+    /// Checks the modification time of the log directory, appends to the active log don't
+    /// change it, so keys written since stay invisible until the next new log.
+    fn refresh(&self, force: bool) -> Result<()> {
+        let watch = match &self.log_watch {
+            Some(watch) => watch,
+            None => return Ok(()),
+        };
+        let mtime = fs::metadata(&*watch.log_dir)?.modified()?;
+        let mut seen = watch.mtime.lock().unwrap();
+        if !force && *seen == mtime {
+            return Ok(());
+        }
+        let (gen_list, index) = loop {
+            if let Some(loaded) = load_all(&watch.log_dir)? {
+                break loaded;
+            }
+        };
+        info!("Logs of {:?} changed, reloaded {} keys", watch.log_dir, index.len());
+        // Logs older than the oldest one left are gone, so readers can close them
+        if let Some(&first) = gen_list.first() {
+            self.reader.curr_version.store(first, Ordering::SeqCst);
+        }
+        for entry in self.index.iter() {
+            if !index.contains_key(entry.key()) {
+                if let Some(value_index) = &self.value_index {
+                    value_index.remove(entry.key());
+                }
+                entry.remove();
+            }
+        }
+        for entry in index.iter() {
+            let (key, cmd_pos) = (entry.key(), *entry.value());
+            if self.index.get(key).map(|entry| *entry.value()) == Some(cmd_pos) {
+                continue;
+            }
+            if let Some(value_index) = &self.value_index {
+                if let Command::Set { value, .. } = self.reader.read_command(key, cmd_pos)? {
+                    value_index.insert(key, &value);
+                }
+            }
+            self.index.insert(key.clone(), cmd_pos);
+        }
+        *seen = mtime;
+        Ok(())
+    }
+
+    /// Reads the value of `key`, removing it if it has expired and `expire` is set.
+    fn read_value(
+        &self,
+        key: String,
+        use_cache: bool,
+        expire: bool,
+    ) -> Result<(Option<String>, ReadStats)> {
+        let cache = if use_cache { self.cache.as_ref() } else { None };
+        let mut stats = ReadStats::default();
+        self.refresh(false)?;
+        if let Some(access) = &self.access {
+            if self.index.contains_key(&key) {
+                access.record_read(&key);
+            }
+        }
+        loop {
+            let cmd_pos = match self.index.get(&key) {
+                Some(cmd_pos) => *cmd_pos.value(),
+                None => return Ok((None, stats)),
+            };
+            if cmd_pos.expired(now_millis()) {
+                if expire && self.writer.is_some() {
+                    self.with_writer(|writer| writer.expire(key, cmd_pos))?;
+                }
+                return Ok((None, stats));
+            }
+            if let Some(cache) = cache {
+                if let Some(value) = cache.lock().unwrap().get((cmd_pos.version, cmd_pos.start)) {
+                    return Ok((Some(value), stats));
+                }
+            }
+            stats.file_open = self.reader.is_open(cmd_pos.version);
+            let cmd = match self.reader.read_command(&key, cmd_pos) {
+                Ok(cmd) => cmd,
+                // A compaction deleted the generation after we looked the key up,
+                // retry with the position the key was moved to. For a read-only store the
+                // compaction ran in the writing process, so the index has to be reloaded.
+                Err(KvError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    self.refresh(true)?;
+                    if self.index.get(&key).map(|entry| *entry.value()) != Some(cmd_pos) {
+                        continue;
+                    }
+                    return Err(e.into());
+                }
+                Err(e) => return Err(e),
+            };
+            stats.bytes_read += cmd_pos.len;
+            return if let Command::Set { value, .. } = cmd {
+                if self.read_repair {
+                    self.repair(&key, &value, cmd_pos);
+                }
+                if let Some(cache) = cache {
+                    // Checked under the cache lock: a write moving the key on invalidates
+                    // the old position after updating the index, so nothing stale stays
+                    // cached after an overwrite or removal.
+                    let mut cache = cache.lock().unwrap();
+                    if self.index.get(&key).map(|entry| *entry.value()) == Some(cmd_pos) {
+                        cache.insert((cmd_pos.version, cmd_pos.start), value.clone());
+                    }
+                }
+                Ok((Some(value), stats))
+            } else if self.lenient_reads {
+                error!(
+                    "Corruption: `{}` points at a removal in log {}, reading it as absent",
+                    key, cmd_pos.version
+                );
+                Ok((None, stats))
+            } else {
+                Err(KvError::UnexpectedCommandType)
+            };
+        }
+    }
+
+    /// The `n` keys read and written most since the store was opened, most accessed first.
+    /// Empty unless enabled with `KvStoreOptions::track_access`.
+    ///
+    /// Only the 1024 keys accessed most are tracked, so keys accessed about as often as
+    /// many others may be missing or ranked too high.
+    pub fn top_keys(&self, n: usize) -> Vec<(String, AccessStats)> {
+        match &self.access {
+            Some(access) => access.top(n),
+            None => Vec::new(),
+        }
+    }
+
+    /// Starts loading many keys at once, much faster than `set`ting them one by one.
+    ///
+    /// The loaded keys are written to a new log. The loader holds the writer lock and
+    /// every key lock until it is finished or dropped, so other writes wait for it, and
+    /// none of the loaded keys can be read before `BulkLoader::finish`.
+    pub fn bulk_loader(&self) -> Result<BulkLoader> {
+        let key_locks = self.key_locks.lock_all();
+        let mut writer = self.writer()?.lock().unwrap();
+        writer.start_generation()?;
+        Ok(BulkLoader {
+            store: self,
+            start: writer.writer.index,
+            writer: Some(writer),
+            entries: Vec::new(),
+            written_at: Some(now_millis()),
+            failed: false,
+            key_locks,
+        })
+    }
+
+    /// Closes the store, syncing the active log and reporting any error doing so or writing
+    /// the snapshot and manifest, which dropping the store can only log.
+    ///
+    /// Fails with `StillOpen` if any clone of the store is still around, or if a background
+    /// compaction or the reaper didn't let go of the writer within 10 seconds. In both
+    /// cases nothing is closed and the error gives the store back.
+    pub fn close(self) -> std::result::Result<(), CloseError> {
+        self.close_within(CLOSE_TIMEOUT)
+    }
+
+    fn close_within(mut self, timeout: Duration) -> std::result::Result<(), CloseError> {
+        let others = Arc::strong_count(&self.handles) - 1;
+        if others > 0 {
+            return Err(CloseError {
+                error: KvError::StillOpen(others),
+                store: Some(Box::new(self)),
+            });
+        }
+        let deadline = Instant::now() + timeout;
+        let writer = loop {
+            let writer = match self.writer.take() {
+                Some(writer) => writer,
+                None => return Ok(()),
+            };
+            match Arc::try_unwrap(writer) {
+                Ok(writer) => break writer,
+                Err(shared) => {
+                    self.writer = Some(shared);
+                    if !self.tasks.wait_idle(deadline) || Instant::now() >= deadline {
+                        return Err(CloseError {
+                            error: KvError::StringError(
+                                "timed out waiting for background tasks to finish".to_owned(),
+                            ),
+                            store: Some(Box::new(self)),
+                        });
+                    }
+                }
+            }
+        };
+        writer.into_inner().unwrap().shut_down(true).map_err(|error| CloseError {
+            error,
+            store: None,
+        })
+    }
+
+    /// Keys whose value starts with `prefix`, in order, see
+    /// `KvStoreOptions::value_prefix_index`.
+    pub fn find_by_value_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        let value_index = self.value_index.as_ref().ok_or_else(|| {
+            KvError::StringError("the value prefix index is not enabled".to_owned())
+        })?;
+        let mut keys = Vec::new();
+        // Candidates may have expired, changed since or only share the indexed prefix
+        for key in value_index.candidates(prefix) {
+            if let (Some(value), _) = self.read_value(key.clone(), true, true)? {
+                if value.starts_with(prefix) {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Writes the index as JSON lines of each key and where its record is, to debug the
+    /// layout of the logs.
+    pub fn dump_index(&self, mut w: impl Write) -> Result<()> {
+        for entry in self.index.iter() {
+            let line = IndexLine {
+                key: entry.key(),
+                index: entry.value(),
+            };
+            serde_json::to_writer(&mut w, &line)?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Opens every log ahead of the first `get` needing it, so that `get` doesn't pay for
+    /// opening the file. Every clone of the store has its own open logs, so this only
+    /// warms up the clone it is called on.
+    pub fn warm_up(&self) -> Result<()> {
+        self.reader.open_all()
+    }
+
+    /// Number of `get`s served from the value cache.
+    pub fn cache_hits(&self) -> u64 {
+        self.cache
+            .as_ref()
+            .map_or(0, |cache| cache.lock().unwrap().hits())
+    }
+}
+
+impl KvEngine for KvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let guard = self.key_locks.lock(&key);
+        self.with_key_locks(vec![guard], |writer| writer.set(key, value, None, None))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_with_stats(key).map(|(value, _)| value)
+    }
+
+    fn stats(&self) -> Stats {
+        KvStore::stats(self)
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        KvStore::ttl(self, key)
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        KvStore::set_with_ttl(self, key, value, ttl)
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, ValueVersion)>> {
+        KvStore::get_versioned(self, key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, version: ValueVersion) -> Result<bool> {
+        KvStore::set_if_version(self, key, value, version)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        KvStore::keys(self)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        KvStore::scan(self, prefix)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let guard = self.key_locks.lock(&key);
+        self.with_key_locks(vec![guard], |writer| writer.remove(key))
+    }
 
-fn load(
-    gen: u64,
-    reader: &mut BufReaderWithIndex<File>,
-    index: &SkipMap<String, CommandIndex>,
-) -> Result<u64> {
-    // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
+    /// Appends to the current value, keeping its expiry and format tag. The value is read
+    /// holding only the lock of `key`, writes to other keys can go on meanwhile.
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        let guard = self.key_locks.lock(&key);
+        let current = self.index.get(&key).map(|entry| *entry.value());
+        let (mut value, expire_at, format_tag) = match (self.get_locked(key.clone())?, current) {
+            (Some(value), Some(cmd_pos)) => (value, cmd_pos.expire_at, Some(cmd_pos.format_tag)),
+            _ => (String::new(), None, None),
+        };
+        value.push_str(&suffix);
+        let len = value.len();
+        self.with_key_locks(vec![guard], |writer| {
+            writer.expire_key(&key)?;
+            writer.set(key, value, expire_at, format_tag)
+        })?;
+        Ok(len)
+    }
+
+    /// Like `append`, keeps the expiry and format tag and only holds the lock of `key`.
+    fn increment_float(&self, key: String, delta: f64) -> Result<f64> {
+        let guard = self.key_locks.lock(&key);
+        let current = self.index.get(&key).map(|entry| *entry.value());
+        let (value, expire_at, format_tag) = match (self.get_locked(key.clone())?, current) {
+            (Some(value), Some(cmd_pos)) => {
+                (Some(value), cmd_pos.expire_at, Some(cmd_pos.format_tag))
+            }
+            _ => (None, None, None),
+        };
+        let value = parse_float(&key, value.as_deref())? + delta;
+        self.with_key_locks(vec![guard], |writer| {
+            writer.expire_key(&key)?;
+            writer.set(key, value.to_string(), expire_at, format_tag)
+        })?;
+        Ok(value)
+    }
+
+    /// Both keys are locked while `from` is read, and the move is a single write.
+    fn rename(&self, from: String, to: String) -> Result<bool> {
+        loop {
+            let guards = self.key_locks.lock_many(&[from.clone(), to.clone()]);
+            let current = self.index.get(&from).map(|entry| *entry.value());
+            let (value, cmd_pos) = match (self.get_locked(from.clone())?, current) {
+                (Some(value), Some(cmd_pos)) => (value, cmd_pos),
+                (None, Some(_)) => {
+                    // Expired, or a removal read as absent with `lenient_reads`
+                    self.with_key_locks(guards, |writer| writer.expire_key(&from))?;
+                    return Ok(false);
                 }
-                index.insert(key, (gen, pos..new_pos).into());
+                _ => return Ok(false),
+            };
+            if from == to {
+                return Ok(true);
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+            let renamed = self.with_key_locks(guards, |writer| {
+                // The key locks don't keep the reaper, a lazy expiry or a compaction from
+                // changing `from` since it was read, look again then
+                if writer.index.get(&from).map(|entry| *entry.value()) != Some(cmd_pos) {
+                    return Ok(false);
                 }
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
+                let (from, to) = (from.clone(), to.clone());
+                writer.rename(from, to, value, cmd_pos.expire_at, cmd_pos.format_tag)?;
+                Ok(true)
+            })?;
+            if renamed {
+                return Ok(true);
             }
         }
-        pos = new_pos;
     }
-    Ok(uncompacted)
-}
-
-struct KvStoreReader {
-    path: Arc<PathBuf>,
-    curr_version: Arc<AtomicU64>,
-    readers: RefCell<BTreeMap<u64, BufReaderWithIndex<File>>>,
-}
 
-impl KvStoreReader {
-    fn remove_timeout_log(&self) {
-        let mut readers = self.readers.borrow_mut();
-        while !readers.is_empty() {
-            let version = *readers.keys().next().unwrap();
-            if version >= self.curr_version.load(Ordering::SeqCst) {
-                break;
+    fn set_many(&self, pairs: Vec<(String, String)>, atomic: bool) -> Result<()> {
+        let keys: Vec<_> = pairs.iter().map(|(key, _)| key.clone()).collect();
+        let guards = self.key_locks.lock_many(&keys);
+        self.with_key_locks(guards, |writer| {
+            if atomic {
+                writer.set_batch(pairs)
+            } else {
+                pairs
+                    .into_iter()
+                    .try_for_each(|(key, value)| writer.set(key, value, None, None))
             }
-            readers.remove(&version);
-        }
+        })
     }
 
-    fn read_command(&self, cmd_index: CommandIndex) -> Result<Command> {
-        self.remove_timeout_log();
-        let mut readers = self.readers.borrow_mut();
-        if !readers.contains_key(&cmd_index.version) {
-            let reader = BufReaderWithIndex::new(File::open(log_path(&self.path, cmd_index.version))?)?;
-            readers.insert(cmd_index.version, reader);
-        }
-        let reader = readers.get_mut(&cmd_index.version).unwrap();
-        reader.seek(SeekFrom::Start(cmd_index.start))?;
-        let cmd_reader = reader.take(cmd_index.len);
-        Ok(serde_json::from_reader(cmd_reader)?)
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let guards = self.key_locks.lock_many(&keys);
+        self.with_key_locks(guards, |writer| writer.remove_many(keys))
     }
 
-    fn read_and<F, R>(&self, cmd_pos: CommandIndex, f: F) -> Result<R>
-        where
-            F: FnOnce(io::Take<&mut BufReaderWithIndex<File>>) -> Result<R>,
-    {
-        self.remove_timeout_log();
-
-        let mut readers = self.readers.borrow_mut();
-        // Open the file if we haven't opened it in this `KvStoreReader`.
-        // We don't use entry API here because we want the errors to be propogated.
-        if !readers.contains_key(&cmd_pos.version) {
-            let reader = BufReaderWithIndex::new(File::open(log_path(&self.path, cmd_pos.version))?)?;
-            readers.insert(cmd_pos.version, reader);
+    fn flush(&self) -> Result<()> {
+        match &self.writer {
+            Some(writer) => Ok(writer.lock().unwrap().writer.sync_all()?),
+            None => Ok(()),
         }
-        let reader = readers.get_mut(&cmd_pos.version).unwrap();
-        reader.seek(SeekFrom::Start(cmd_pos.start))?;
-        let cmd_reader = reader.take(cmd_pos.len);
-        f(cmd_reader)
     }
-}
 
-impl Clone for KvStoreReader {
-    fn clone(&self) -> Self {
-        KvStoreReader {
-            path: Arc::clone(&self.path),
-            curr_version: Arc::clone(&self.curr_version),
-            readers: RefCell::new(BTreeMap::new()),
-        }
+    fn meta(&self, key: String) -> Result<KeyMeta> {
+        let now = now_millis();
+        Ok(match self.index.get(&key).filter(|entry| !entry.value().expired(now)) {
+            Some(cmd_pos) => KeyMeta {
+                exists: true,
+                len: cmd_pos.value().value_len,
+                version: cmd_pos.value().version,
+            },
+            None => KeyMeta::default(),
+        })
     }
 }
 
-struct KvStoreWriter {
-    reader: KvStoreReader,
-    writer: BufWriterWithIndex<File>,
-    curr_version: u64,
-    uncompacted: u64,
-    path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandIndex>>,
+/// Loads keys into a store in bulk, see `KvStore::bulk_loader`.
+///
+/// Records are only flushed and indexed by `finish`, dropping the loader without calling it
+/// discards everything loaded.
+pub struct BulkLoader<'a> {
+    store: &'a KvStore,
+    /// `None` once finished.
+    writer: Option<MutexGuard<'a, KvStoreWriter>>,
+    /// Where the loaded records start in the active log.
+    start: u64,
+    /// Loaded keys with their value prefix, if the store has a value prefix index.
+    entries: Vec<(String, CommandIndex, Option<String>)>,
+    written_at: Option<u64>,
+    /// Whether a partial record couldn't be removed from the log, so only dropping the
+    /// loader is left.
+    failed: bool,
+    /// Declared last, so they are released after the log was rolled back.
+    key_locks: Vec<MutexGuard<'a, ()>>,
 }
 
-impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.index;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set {key, ..} = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
+impl<'a> BulkLoader<'a> {
+    /// Appends a `Set` of `key`, which is not visible until the load is finished.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.check_failed()?;
+        let writer = self.writer.as_mut().expect("bulk load is finished");
+        writer.check_entry(&key, &value)?;
+        let format_tag = writer.format_for(&key);
+        let value_len = value.len() as u64;
+        let value_prefix = writer
+            .value_index
+            .as_ref()
+            .map(|value_index| value_index.prefix(&value).to_owned());
+        let cmd = Command::Set {
+            key,
+            value,
+            expire_at: None,
+            written_at: self.written_at,
+            format_tag,
+        };
+        let start = writer.writer.index;
+        if let Err(e) = serde_json::to_writer(&mut writer.writer, &cmd) {
+            if let Err(e) = writer.writer.truncate(start) {
+                error!("Failed to remove a partial record of a bulk load: {}", e);
+                self.failed = true;
             }
-            self.index.insert(key, (self.curr_version, pos..self.writer.index).into());
+            return Err(e.into());
         }
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+        if let Command::Set { key, .. } = cmd {
+            let cmd_pos: CommandIndex =
+                (writer.curr_version, start..writer.writer.index, value_len).into();
+            let cmd_pos = CommandIndex { written_at: self.written_at, format_tag, ..cmd_pos };
+            self.entries.push((key, cmd_pos, value_prefix));
         }
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            let pos = self.writer.index;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove {key} = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.value().len;
-                self.uncompacted += self.writer.index - pos;
+    /// Makes the loaded keys durable and readable, returning how many records were loaded.
+    pub fn finish(mut self) -> Result<usize> {
+        self.check_failed()?;
+        let mut writer = self.writer.take().expect("bulk load is finished");
+        if let Err(e) = writer.sync() {
+            rollback(&mut writer, self.start);
+            return Err(e);
+        }
+        let loaded = self.entries.len();
+        writer.written_bytes += writer.writer.index - self.start;
+        for (key, cmd_pos, value_prefix) in self.entries.drain(..) {
+            if let (Some(value_index), Some(value_prefix)) = (&writer.value_index, value_prefix) {
+                value_index.insert(&key, &value_prefix);
             }
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
+            writer.index_insert(key, cmd_pos);
+        }
+        let res = writer.maybe_compact();
+        let evicted = mem::replace(&mut writer.evicted, Vec::new());
+        drop(writer);
+        // The hook may write to the store
+        self.key_locks.clear();
+        if let Some(on_evict) = &self.store.on_evict {
+            for key in evicted {
+                (on_evict.0)(&key);
             }
-            Ok(())
-        } else {
-            Err(KvError::KeyNotFound)
         }
+        res.map(|_| loaded)
     }
-    fn compact(&mut self) -> Result<()> {
-        let compact_version = self.curr_version + 1;
-        self.curr_version += 2;
-
-        self.writer = new_log_file(&self.path, self.curr_version)?;
-
-        let mut compact_writer = new_log_file(&self.path, compact_version)?;
-
-        let mut new_pos = 0;
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compact_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compact_version, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
-        }
-        compact_writer.flush()?;
-        self.reader.curr_version.store(compact_version, Ordering::SeqCst);
-        self.reader.remove_timeout_log();
-
-        let stale_gens = get_log_list(&self.path)?
-            .into_iter()
-            .filter(|&gen| gen < compact_version);
 
-        for stale_gen in stale_gens {
-            let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, e);
-            }
+    fn check_failed(&self) -> Result<()> {
+        if self.failed {
+            return Err(KvError::StringError(
+                "bulk load failed and can only be dropped".to_owned(),
+            ));
         }
-        self.uncompacted = 0;
-
         Ok(())
     }
 }
 
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithIndex<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithIndex::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
-    Ok(writer)
+impl<'a> Drop for BulkLoader<'a> {
+    fn drop(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            rollback(writer, self.start);
+        }
+    }
 }
 
-#[derive(Clone)]
-pub struct KvStore {
-    path: Arc<PathBuf>,
+fn rollback(writer: &mut KvStoreWriter, pos: u64) {
+    if let Err(e) = writer.writer.truncate(pos) {
+        error!("Failed to roll back the log after an unfinished bulk load: {}", e);
+    }
+}
 
-    index: Arc<SkipMap<String, CommandIndex>>,
+/// State of a key as returned by `KvStore::get_state`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyState {
+    Present(String),
+    Tombstoned,
+    Absent,
+}
 
-    reader: KvStoreReader,
+/// 操作类型，序列化到日志中，便于后续恢复
+#[derive(Deserialize, Serialize, Debug)]
+enum Command {
+    Set {
+        key: String,
+        value: String,
+        /// Unix time in milliseconds after which the value is gone.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expire_at: Option<u64>,
+        /// Unix time in milliseconds of the write, missing in logs of older versions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        written_at: Option<u64>,
+        /// How the value is encoded, only recorded for tools. `RAW_FORMAT` is not written.
+        #[serde(default, skip_serializing_if = "is_raw_format")]
+        format_tag: u8,
+    },
+    Remove { key: String },
+}
 
-    writer: Arc<Mutex<KvStoreWriter>>,
+/// Written at the start of every log, so its records can be decoded by the format it was
+/// written in. Older builds skip it like a record of an unknown command.
+#[derive(Serialize, Deserialize)]
+enum LogHeader {
+    Format(u32),
 }
 
-impl KvStore {
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = Arc::new(path.into());
-        fs::create_dir_all(&*path)?;
+fn log_header_len() -> u64 {
+    serde_json::to_vec(&LogHeader::Format(LOG_FORMAT)).map_or(0, |header| header.len() as u64)
+}
 
-        let mut readers = BTreeMap::new();
-        let index = Arc::new(SkipMap::new());
+/// A log record as read back by `load`.
+///
+/// Records of commands added by newer versions are kept as `Unknown` and skipped, so
+/// older code can still open logs written by newer code.
+enum LogRecord {
+    Known(Command),
+    /// The tag of the unknown command.
+    Unknown(String),
+}
 
-        let gen_list = get_log_list(&path)?;
-        let mut uncompacted = 0;
+impl<'de> Deserialize<'de> for LogRecord {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(LogRecordVisitor)
+    }
+}
 
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithIndex::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
-            readers.insert(gen, reader);
-        }
+struct LogRecordVisitor;
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen)?;
-        let safe_point = Arc::new(AtomicU64::new(0));
+impl<'de> Visitor<'de> for LogRecordVisitor {
+    type Value = LogRecord;
 
-        let reader = KvStoreReader {
-            path: Arc::clone(&path),
-            curr_version: safe_point,
-            readers: RefCell::new(readers),
-        };
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map with a single command")
+    }
 
-        let writer = KvStoreWriter {
-            reader: reader.clone(),
-            writer,
-            curr_version: current_gen,
-            uncompacted,
-            path: Arc::clone(&path),
-            index: Arc::clone(&index),
+    fn visit_map<A>(self, mut map: A) -> std::result::Result<LogRecord, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let tag: String = map.next_key()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        // Known commands are decoded straight from the stream, only unknown ones are skipped
+        let record = if Command::TAGS.contains(&tag.as_str()) {
+            let tagged = TaggedMap { tag: Some(tag), map: &mut map };
+            LogRecord::Known(Command::deserialize(MapAccessDeserializer::new(tagged))?)
+        } else {
+            map.next_value::<IgnoredAny>()?;
+            LogRecord::Unknown(tag)
         };
-
-        Ok(KvStore {
-            path,
-            reader,
-            index,
-            writer: Arc::new(Mutex::new(writer)),
-        })
-
+        if map.next_key::<IgnoredAny>()?.is_some() {
+            return Err(de::Error::custom("more than one command in a record"));
+        }
+        Ok(record)
     }
 }
 
-impl KvEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
-    }
+/// `map` with its first key, already read as `tag`, put back in front.
+struct TaggedMap<'a, A> {
+    tag: Option<String>,
+    map: &'a mut A,
+}
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            if let Command::Set { value, .. } = self.reader.read_command(*cmd_pos.value())? {
-                Ok(Some(value))
-            } else {
-                Err(KvError::UnexpectedCommandType)
-            }
-        } else {
-            Ok(None)
+impl<'de, 'a, A> MapAccess<'de> for TaggedMap<'a, A>
+where
+    A: MapAccess<'de>,
+{
+    type Error = A::Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> std::result::Result<Option<K::Value>, A::Error>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        match self.tag.take() {
+            Some(tag) => seed.deserialize(tag.into_deserializer()).map(Some),
+            None => self.map.next_key_seed(seed),
         }
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    fn next_value_seed<V>(&mut self, seed: V) -> std::result::Result<V::Value, A::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        self.map.next_value_seed(seed)
     }
 }
 
-/// 操作类型，序列化到日志中，便于后续恢复
-#[derive(Deserialize, Serialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
-}
-
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    /// Tags of all `Command` variants as serialized in the log.
+    const TAGS: &'static [&'static str] = &["Set", "Remove"];
+
+    fn payload_len(&self) -> usize {
+        match self {
+            Command::Set { key, value, .. } => key.len() + value.len(),
+            Command::Remove { key } => key.len(),
+        }
     }
 
     fn remove(key: String) -> Command {
@@ -326,19 +2663,50 @@ impl Command {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct CommandIndex {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct CommandIndex {
     version: u64,
     start: u64,
     len: u64,
+    value_len: u64,
+    expire_at: Option<u64>,
+    written_at: Option<u64>,
+    #[serde(default)]
+    format_tag: u8,
+}
+
+fn is_raw_format(tag: &u8) -> bool {
+    *tag == RAW_FORMAT
+}
+
+/// A line of `KvStore::dump_index`.
+#[derive(Serialize)]
+struct IndexLine<'a> {
+    key: &'a str,
+    #[serde(flatten)]
+    index: &'a CommandIndex,
+}
+
+impl CommandIndex {
+    fn expired(&self, now: u64) -> bool {
+        self.expire_at.map_or(false, |expire_at| expire_at <= now)
+    }
+
+    fn value_version(&self) -> ValueVersion {
+        ValueVersion::new(self.version, self.start)
+    }
 }
 
-impl From<(u64, Range<u64>)> for CommandIndex {
-    fn from((v, range): (u64, Range<u64>)) -> Self {
+impl From<(u64, Range<u64>, u64)> for CommandIndex {
+    fn from((v, range, value_len): (u64, Range<u64>, u64)) -> Self {
         CommandIndex {
             version: v,
             start: range.start,
             len: range.end - range.start,
+            value_len,
+            expire_at: None,
+            written_at: None,
+            format_tag: RAW_FORMAT,
         }
     }
 }
@@ -346,6 +2714,10 @@ impl From<(u64, Range<u64>)> for CommandIndex {
 struct BufReaderWithIndex<R: Read + Seek> {
     reader: BufReader<R>,
     index: u64,
+    /// Format of the log, see `read_log_header`.
+    format: u32,
+    /// Where the records start, after the header.
+    records_start: u64,
 }
 
 impl<R: Read + Seek> BufReaderWithIndex<R> {
@@ -354,6 +2726,8 @@ impl<R: Read + Seek> BufReaderWithIndex<R> {
         Ok(BufReaderWithIndex {
             reader: BufReader::new(inner),
             index,
+            format: LEGACY_LOG_FORMAT,
+            records_start: 0,
         })
     }
 }
@@ -388,6 +2762,22 @@ impl<W: Write + Seek> BufWriterWithIndex<W> {
     }
 }
 
+impl BufWriterWithIndex<File> {
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+
+    /// Discards anything buffered or written after `pos`.
+    fn truncate(&mut self, pos: u64) -> io::Result<()> {
+        let file = self.writer.get_ref().try_clone()?;
+        // `into_parts` hands back the buffer instead of flushing it on drop
+        let (file, _) = mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+        self.index = pos;
+        file.set_len(pos)
+    }
+}
+
 impl<W: Write + Seek> Write for BufWriterWithIndex<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
@@ -407,3 +2797,276 @@ impl<W: Write + Seek> Seek for BufWriterWithIndex<W> {
         Ok(pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::mpsc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn reader_survives_panic_in_closure() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let cmd_pos = *store.index.get("key1").unwrap().value();
+
+        let reader = store.reader.clone();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            reader.read_and(cmd_pos, |_| -> Result<()> { panic!("read failed") })
+        }));
+        assert!(res.is_err());
+
+        match reader.read_command("key1", cmd_pos)? {
+            Command::Set { value, .. } => assert_eq!(value, "value1"),
+            cmd => panic!("unexpected command: {:?}", cmd),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_runs_on_maintenance_pool() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().compaction_ratio(0.5).maintenance_threads(1);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+        // Keep the only maintenance thread busy while writing
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let writer = store.writer()?.lock().unwrap();
+        writer.maintenance.as_ref().unwrap().pool.spawn(move || {
+            started_tx.send(thread::current().name().map(str::to_owned)).unwrap();
+            release_rx.recv().unwrap();
+        });
+        drop(writer);
+        assert_eq!(started_rx.recv().unwrap(), Some("kv-maintenance-0".to_owned()));
+
+        for _ in 0..10 {
+            store.set("key1".to_owned(), "value1".to_owned())?;
+        }
+        // Due, but not run by the writes themselves
+        assert_eq!(store.stats().compactions, 0);
+        release_tx.send(()).unwrap();
+        for _ in 0..500 {
+            if store.stats().compactions > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(store.stats().compactions, 1);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn index_mismatch() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        let key1_pos = *store.index.get("key1").unwrap().value();
+        let key2_pos = *store.index.get("key2").unwrap().value();
+
+        // Pointing at the record of another key
+        store.index.insert("key1".to_owned(), key2_pos);
+        match store.get("key1".to_owned()) {
+            Err(KvError::IndexMismatch { key, version }) => {
+                assert_eq!(key, "key1");
+                assert_eq!(version, key2_pos.version);
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // Pointing into the middle of a record
+        let shifted = CommandIndex {
+            start: key1_pos.start + 1,
+            ..key1_pos
+        };
+        store.index.insert("key1".to_owned(), shifted);
+        match store.get("key1".to_owned()) {
+            Err(KvError::IndexMismatch { key, .. }) => assert_eq!(key, "key1"),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn removal_in_place_of_value() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        for &lenient in &[false, true] {
+            let path = temp_dir.path().join(lenient.to_string());
+            let options = KvStoreOptions::default().lenient_reads(lenient);
+            let store = KvStore::open_with_options(path, options)?;
+            store.set("key1".to_owned(), "value1".to_owned())?;
+            let value_pos = *store.index.get("key1").unwrap().value();
+            let removal_start = store.writer()?.lock().unwrap().writer.index;
+            store.remove("key1".to_owned())?;
+            let removal_end = store.writer()?.lock().unwrap().writer.index;
+
+            let removal_pos = CommandIndex {
+                start: removal_start,
+                len: removal_end - removal_start,
+                ..value_pos
+            };
+            store.index.insert("key1".to_owned(), removal_pos);
+            match store.get("key1".to_owned()) {
+                Ok(None) if lenient => {}
+                Err(KvError::UnexpectedCommandType) if !lenient => {}
+                res => panic!("unexpected result with lenient {}: {:?}", lenient, res),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn key_locks_only_block_their_stripe() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        let other = (0..)
+            .map(|i| format!("key{}", i))
+            .find(|key| store.key_locks.stripe(key) != store.key_locks.stripe("key"))
+            .unwrap();
+
+        // An append to `key` in the middle of its read only holds up writes to `key`
+        let (tx, rx) = std::sync::mpsc::channel();
+        let guard = store.key_locks.lock("key");
+        let handles: Vec<_> = vec!["key".to_owned(), other.clone()]
+            .into_iter()
+            .map(|key| {
+                let (store, tx) = (store.clone(), tx.clone());
+                std::thread::spawn(move || {
+                    store.append(key.clone(), "a".to_owned()).unwrap();
+                    tx.send(key).unwrap();
+                })
+            })
+            .collect();
+        assert_eq!(rx.recv().unwrap(), other);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(guard);
+        assert_eq!(rx.recv().unwrap(), "key");
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Concurrent appends to one key don't lose updates
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        store.append("counter".to_owned(), "x".to_owned()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(store.get("counter".to_owned())?.map(|v| v.len()), Some(200));
+        Ok(())
+    }
+
+    /// Makes every write of `store` fail with ENOSPC, returning the active log to put back.
+    #[cfg(target_os = "linux")]
+    fn fill_disk(store: &KvStore) -> Result<BufWriterWithIndex<File>> {
+        let full = OpenOptions::new().write(true).open("/dev/full")?;
+        let mut writer = store.writer().unwrap().lock().unwrap();
+        let mut full = BufWriterWithIndex::new(full)?;
+        full.index = writer.writer.index;
+        Ok(mem::replace(&mut writer.writer, full))
+    }
+
+    // A write failing with ENOSPC must leave the index and the log consistent.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_write_rolls_back() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let real = fill_disk(&store)?;
+        assert!(store.set("key1".to_owned(), "value2".to_owned()).is_err());
+        assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+        {
+            let writer = store.writer().unwrap().lock().unwrap();
+            assert_eq!(writer.writer.index, real.index);
+            assert!(writer.writer.writer.buffer().is_empty());
+        }
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        // Space was freed
+        store.writer().unwrap().lock().unwrap().writer = real;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(())
+    }
+
+    // Background tasks hold the writer for a moment, which mustn't count as another handle.
+    #[test]
+    fn close_waits_for_background_tasks() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let started = store.tasks.start();
+        let writer = Arc::clone(store.writer.as_ref().unwrap());
+        let task = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(writer);
+            drop(started);
+        });
+        store.close()?;
+        task.join().unwrap();
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn close_times_out() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        let started = store.tasks.start();
+        let writer = Arc::clone(store.writer.as_ref().unwrap());
+
+        let store = store
+            .close_within(Duration::from_millis(50))
+            .unwrap_err()
+            .into_store()
+            .expect("the store is given back");
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(writer);
+        drop(started);
+        store.close()?;
+        Ok(())
+    }
+
+    // A drain that can't log its removals must not remove anything.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_drain_keeps_entries() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+
+        let real = fill_disk(&store)?;
+        assert!(store.drain().is_err());
+        assert!(store.clear().is_err());
+        assert_eq!(store.len(), 100);
+        assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+
+        store.writer().unwrap().lock().unwrap().writer = real;
+        assert_eq!(store.drain()?.len(), 100);
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert!(store.is_empty());
+        Ok(())
+    }
+}
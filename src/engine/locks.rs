@@ -0,0 +1,47 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+
+const STRIPES: usize = 64;
+
+/// Per-key locks striped over a fixed number of mutexes.
+///
+/// Read-modify-write operations hold the lock of their key while reading, so they stay
+/// atomic without keeping writes to other keys waiting.
+pub(crate) struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl KeyLocks {
+    pub(crate) fn new() -> KeyLocks {
+        KeyLocks {
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    pub(crate) fn stripe(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.stripes.len()
+    }
+
+    pub(crate) fn lock(&self, key: &str) -> MutexGuard<()> {
+        self.stripes[self.stripe(key)].lock().unwrap()
+    }
+
+    /// Locks the stripes of all `keys`, always in the same order to not deadlock.
+    pub(crate) fn lock_many(&self, keys: &[String]) -> Vec<MutexGuard<()>> {
+        let mut stripes: Vec<_> = keys.iter().map(|key| self.stripe(key)).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|stripe| self.stripes[stripe].lock().unwrap())
+            .collect()
+    }
+
+    /// Locks every stripe, blocking all other read-modify-write operations.
+    pub(crate) fn lock_all(&self) -> Vec<MutexGuard<()>> {
+        self.stripes.iter().map(|stripe| stripe.lock().unwrap()).collect()
+    }
+}
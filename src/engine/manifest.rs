@@ -0,0 +1,87 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::kv::{get_log_list, log_path};
+use crate::{KvError, Result};
+
+const MANIFEST: &str = "MANIFEST";
+
+/// Size and checksum of every log, written on clean shutdown.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    generations: Vec<GenerationSum>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct GenerationSum {
+    version: u64,
+    size: u64,
+    crc: u32,
+}
+
+fn checksum(path: &Path) -> Result<GenerationSum> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = [0; 8192];
+    let mut size = 0;
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        size += len as u64;
+    }
+    Ok(GenerationSum {
+        version: 0,
+        size,
+        crc: hasher.finalize(),
+    })
+}
+
+pub(super) fn write_manifest(meta_dir: &Path, log_dir: &Path) -> Result<()> {
+    let generations = get_log_list(log_dir)?
+        .into_iter()
+        .map(|version| {
+            let sum = checksum(&log_path(log_dir, version))?;
+            Ok(GenerationSum { version, ..sum })
+        })
+        .collect::<Result<_>>()?;
+    let manifest = serde_json::to_vec(&Manifest { generations })?;
+    fs::write(meta_dir.join(MANIFEST), manifest)?;
+    Ok(())
+}
+
+/// Checks the logs against the manifest left by the last clean shutdown and removes it,
+/// the logs are going to change from now on.
+pub(super) fn verify_manifest(meta_dir: &Path, log_dir: &Path, verify: bool) -> Result<()> {
+    let path = meta_dir.join(MANIFEST);
+    let manifest: Manifest = match fs::read(&path) {
+        Ok(content) => serde_json::from_slice(&content)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if verify {
+        for expected in manifest.generations {
+            let log = log_path(log_dir, expected.version);
+            if !log.exists() {
+                return Err(KvError::Corruption(format!("{:?} is missing", log)));
+            }
+            let found = GenerationSum {
+                version: expected.version,
+                ..checksum(&log)?
+            };
+            if found != expected {
+                return Err(KvError::Corruption(format!(
+                    "{:?} doesn't match the manifest",
+                    log
+                )));
+            }
+        }
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
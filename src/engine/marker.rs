@@ -0,0 +1,106 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::Layout;
+use crate::{KvError, Result};
+
+const ENGINE_MARKER: &str = "engine";
+
+/// Storage engines a data directory can be created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineKind {
+    KvStore,
+    Sled,
+}
+
+impl EngineKind {
+    pub const ALL: [EngineKind; 2] = [EngineKind::KvStore, EngineKind::Sled];
+
+    /// Whether this build can open stores of this engine.
+    pub fn is_available(self) -> bool {
+        match self {
+            EngineKind::KvStore => true,
+            // Not implemented yet, so no build has it
+            EngineKind::Sled => false,
+        }
+    }
+
+    /// Engines this build can open stores of.
+    pub fn available() -> Vec<EngineKind> {
+        EngineKind::ALL.iter().cloned().filter(|kind| kind.is_available()).collect()
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineKind::KvStore => write!(f, "kvstore"),
+            EngineKind::Sled => write!(f, "sled"),
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "kvstore" => Ok(EngineKind::KvStore),
+            "sled" => Ok(EngineKind::Sled),
+            other => Err(format!("unknown engine `{}`", other)),
+        }
+    }
+}
+
+fn read_marker(dir: &Path) -> Result<Option<EngineKind>> {
+    let marker = dir.join(ENGINE_MARKER);
+    if !marker.exists() {
+        return Ok(None);
+    }
+    match fs::read_to_string(&marker)?.parse() {
+        Ok(kind) => Ok(Some(kind)),
+        Err(e) => {
+            warn!("The content of engine file is invalid: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Returns the engine the data directory `path` was created with, if it has a valid marker.
+pub fn detect_engine(path: &Path) -> Result<Option<EngineKind>> {
+    for layout in &[Layout::Flat, Layout::Subfolders] {
+        if let Some(kind) = read_marker(&layout.meta_dir(path))? {
+            return Ok(Some(kind));
+        }
+    }
+    Ok(None)
+}
+
+/// Like `check_engine`, but leaves a directory without marker alone.
+pub(crate) fn expect_engine(dir: &Path, kind: EngineKind) -> Result<()> {
+    match read_marker(dir)? {
+        Some(found) if found != kind => Err(KvError::WrongEngine {
+            expected: kind,
+            found,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Checks the engine marker in `dir` against `kind`, writing it if the directory has none yet.
+pub fn check_engine(dir: &Path, kind: EngineKind) -> Result<()> {
+    match read_marker(dir)? {
+        Some(found) if found == kind => return Ok(()),
+        Some(found) => {
+            return Err(KvError::WrongEngine {
+                expected: kind,
+                found,
+            })
+        }
+        None => {}
+    }
+    fs::write(dir.join(ENGINE_MARKER), format!("{}", kind))?;
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use super::{parse_float, KeyMeta, KvEngine, Stats};
+use crate::{KvError, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Engine keeping everything in memory, nothing survives the process.
+///
+/// Values are stored as `Arc<str>` so `get_ref` can hand them out without copying.
+#[derive(Clone, Default)]
+pub struct MemoryKvEngine {
+    map: Arc<RwLock<HashMap<String, Arc<str>>>>,
+    /// Most recent values of each key, oldest first, only kept when `history_len > 0`.
+    history: Arc<Mutex<HashMap<String, VecDeque<Arc<str>>>>>,
+    history_len: usize,
+}
+
+impl MemoryKvEngine {
+    pub fn new() -> MemoryKvEngine {
+        MemoryKvEngine::default()
+    }
+
+    /// Creates an engine remembering the last `len` values written to each key, see
+    /// `get_history`.
+    pub fn with_history(len: usize) -> MemoryKvEngine {
+        MemoryKvEngine {
+            history_len: len,
+            ..MemoryKvEngine::default()
+        }
+    }
+
+    /// Returns the most recent values of `key`, oldest first and ending with the current
+    /// one. Always empty unless created by `with_history`, removing a key forgets them.
+    pub fn get_history(&self, key: &str) -> Vec<String> {
+        let history = self.history.lock().unwrap();
+        history.get(key).map_or_else(Vec::new, |values| {
+            values.iter().map(|value| value.to_string()).collect()
+        })
+    }
+
+    /// Called with the map write lock held, so history is updated in the same order.
+    fn record(&self, key: &str, value: &Arc<str>) {
+        if self.history_len == 0 {
+            return;
+        }
+        let mut history = self.history.lock().unwrap();
+        let values = history.entry(key.to_owned()).or_insert_with(VecDeque::new);
+        if values.len() == self.history_len {
+            values.pop_front();
+        }
+        values.push_back(value.clone());
+    }
+
+    fn forget(&self, key: &str) {
+        if self.history_len > 0 {
+            self.history.lock().unwrap().remove(key);
+        }
+    }
+}
+
+impl KvEngine for MemoryKvEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if key.is_empty() {
+            return Err(KvError::InvalidKey("key is empty".to_owned()));
+        }
+        let mut map = self.map.write().unwrap();
+        let value = Arc::from(value);
+        self.record(&key, &value);
+        map.insert(key, value);
+        Ok(())
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>, _atomic: bool) -> Result<()> {
+        // Checked up front, so every batch is atomic
+        if pairs.iter().any(|(key, _)| key.is_empty()) {
+            return Err(KvError::InvalidKey("key is empty".to_owned()));
+        }
+        let mut map = self.map.write().unwrap();
+        for (key, value) in pairs {
+            let value = Arc::from(value);
+            self.record(&key, &value);
+            map.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.map.read().unwrap().get(&key).map(|value| value.to_string()))
+    }
+
+    fn get_ref(&self, key: String) -> Result<Option<Arc<str>>> {
+        Ok(self.map.read().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut map = self.map.write().unwrap();
+        match map.remove(&key) {
+            Some(_) => {
+                self.forget(&key);
+                Ok(())
+            }
+            None => Err(KvError::KeyNotFound),
+        }
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        if key.is_empty() {
+            return Err(KvError::InvalidKey("key is empty".to_owned()));
+        }
+        let mut map = self.map.write().unwrap();
+        let mut value = map.get(&key).map(|value| value.to_string()).unwrap_or_default();
+        value.push_str(&suffix);
+        let len = value.len();
+        let value = Arc::from(value);
+        self.record(&key, &value);
+        map.insert(key, value);
+        Ok(len)
+    }
+
+    fn increment_float(&self, key: String, delta: f64) -> Result<f64> {
+        if key.is_empty() {
+            return Err(KvError::InvalidKey("key is empty".to_owned()));
+        }
+        let mut map = self.map.write().unwrap();
+        let value = parse_float(&key, map.get(&key).map(|value| &**value))? + delta;
+        let stored = Arc::from(value.to_string());
+        self.record(&key, &stored);
+        map.insert(key, stored);
+        Ok(value)
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<bool> {
+        if to.is_empty() {
+            return Err(KvError::InvalidKey("key is empty".to_owned()));
+        }
+        let mut map = self.map.write().unwrap();
+        if from == to {
+            return Ok(map.contains_key(&from));
+        }
+        let value = match map.remove(&from) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        self.forget(&from);
+        self.record(&to, &value);
+        map.insert(to, value);
+        Ok(true)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut keys: Vec<_> = self.map.read().unwrap().keys().cloned().collect();
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    fn stats(&self) -> Stats {
+        let map = self.map.read().unwrap();
+        let mut stats = Stats {
+            keys: map.len() as u64,
+            ..Stats::default()
+        };
+        for value in map.values() {
+            stats.record_value_size(value.len() as u64);
+        }
+        stats
+    }
+
+    fn meta(&self, key: String) -> Result<KeyMeta> {
+        Ok(match self.map.read().unwrap().get(&key) {
+            Some(value) => KeyMeta {
+                exists: true,
+                len: value.len() as u64,
+                version: 0,
+            },
+            None => KeyMeta::default(),
+        })
+    }
+}
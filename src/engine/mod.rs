@@ -1,11 +1,210 @@
-use super::Result;
+use super::{KvError, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Cheap metadata about a key, see `KvEngine::meta`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyMeta {
+    pub exists: bool,
+    /// Length of the value in bytes.
+    pub len: u64,
+    /// Generation of the log holding the value.
+    pub version: u64,
+}
+
+/// Identifies the value a key held when it was read, see `KvEngine::get_versioned`.
+///
+/// Any write of the key changes it. Moving the value in a compaction changes it too, so
+/// a conditional write may fail although the key wasn't written in the meantime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueVersion {
+    generation: u64,
+    offset: u64,
+}
+
+impl ValueVersion {
+    pub(crate) fn new(generation: u64, offset: u64) -> ValueVersion {
+        ValueVersion { generation, offset }
+    }
+}
+
+/// Reads the value of `key` for `KvEngine::increment_float`.
+pub(crate) fn parse_float(key: &str, value: Option<&str>) -> Result<f64> {
+    match value {
+        Some(value) => value.parse().map_err(|_| KvError::NotANumber(key.to_owned())),
+        None => Ok(0.0),
+    }
+}
 
 pub trait KvEngine: Clone + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
     fn get(&self, key: String) -> Result<Option<String>>;
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Like `get`, but engines holding shared values can return them without copying.
+    fn get_ref(&self, key: String) -> Result<Option<Arc<str>>> {
+        Ok(self.get(key)?.map(Arc::from))
+    }
+
+    /// Makes every acknowledged write durable, a no-op for engines without buffering.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Appends `suffix` to the value of `key` (empty if absent) and returns the new length.
+    ///
+    /// The default implementation isn't atomic, engines should override it.
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        let mut value = self.get(key.clone())?.unwrap_or_default();
+        value.push_str(&suffix);
+        let len = value.len();
+        self.set(key, value)?;
+        Ok(len)
+    }
+
+    /// Adds `delta` to the value of `key` read as a float (0 if absent) and returns the sum.
+    ///
+    /// The default implementation isn't atomic, engines should override it.
+    fn increment_float(&self, key: String, delta: f64) -> Result<f64> {
+        let value = parse_float(&key, self.get(key.clone())?.as_deref())? + delta;
+        self.set(key, value.to_string())?;
+        Ok(value)
+    }
+
+    /// Moves the value of `from` to `to`, overwriting it, and returns whether `from` existed.
+    ///
+    /// The default implementation isn't atomic, engines should override it.
+    fn rename(&self, from: String, to: String) -> Result<bool> {
+        let value = match self.get(from.clone())? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if from != to {
+            self.set(to, value)?;
+            self.remove(from)?;
+        }
+        Ok(true)
+    }
+
+    /// Removes `key` if it exists, returning whether it did instead of failing on a missing key.
+    fn remove_if_exists(&self, key: String) -> Result<bool> {
+        match self.remove(key) {
+            Ok(()) => Ok(true),
+            Err(KvError::KeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sets all `pairs` in order. If `atomic`, an error leaves none of them applied.
+    ///
+    /// The default implementation can't undo a partial batch, so it rejects atomic ones.
+    fn set_many(&self, pairs: Vec<(String, String)>, atomic: bool) -> Result<()> {
+        if atomic {
+            return Err(KvError::StringError(
+                "atomic batches are not supported by this engine".to_owned(),
+            ));
+        }
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Removes every present key in `keys`, reporting positionally which ones existed.
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        keys.into_iter()
+            .map(|key| match self.remove(key) {
+                Ok(()) => Ok(true),
+                Err(KvError::KeyNotFound) => Ok(false),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    /// Like `get`, but also returns the version of the value for `set_if_version`.
+    fn get_versioned(&self, _key: String) -> Result<Option<(String, ValueVersion)>> {
+        Err(KvError::StringError(
+            "versioned reads are not supported by this engine".to_owned(),
+        ))
+    }
+
+    /// Sets `key` to `value` only if it still holds the value of `version`, returning
+    /// whether it did.
+    fn set_if_version(&self, _key: String, _value: String, _version: ValueVersion) -> Result<bool> {
+        Err(KvError::StringError(
+            "versioned writes are not supported by this engine".to_owned(),
+        ))
+    }
+
+    /// All keys in order, for tools going over the whole store like `migrate`.
+    fn keys(&self) -> Result<Vec<String>> {
+        Err(KvError::StringError(
+            "listing keys is not supported by this engine".to_owned(),
+        ))
+    }
+
+    /// All pairs whose key starts with `prefix`, in key order.
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for key in self.keys()? {
+            if key.starts_with(&prefix) {
+                if let Some(value) = self.get(key.clone())? {
+                    pairs.push((key, value));
+                }
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Point-in-time statistics, engines fill in what applies to them.
+    fn stats(&self) -> Stats {
+        Stats::default()
+    }
+
+    /// Time `key` has left before it expires, `None` if it is absent or doesn't expire.
+    fn ttl(&self, _key: String) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    /// Sets `key` to `value`, expiring it after `ttl`.
+    fn set_with_ttl(&self, _key: String, _value: String, _ttl: Duration) -> Result<()> {
+        Err(KvError::StringError(
+            "expiring keys are not supported by this engine".to_owned(),
+        ))
+    }
+
+    /// Returns metadata about `key` without fetching the value where the engine can.
+    fn meta(&self, key: String) -> Result<KeyMeta> {
+        Ok(match self.get(key)? {
+            Some(value) => KeyMeta {
+                exists: true,
+                len: value.len() as u64,
+                version: 0,
+            },
+            None => KeyMeta::default(),
+        })
+    }
 }
 
-pub use self::kv::KvStore;
+pub use self::access::AccessStats;
+pub use self::kv::{BulkLoader, CloseError, KeyState, KvStore};
+pub use self::marker::{check_engine, detect_engine, EngineKind};
+pub(crate) use self::marker::expect_engine;
+pub use self::memory::MemoryKvEngine;
+pub use self::options::{CompactionOrder, KvStoreOptions, Layout, SyncPolicy};
+pub use self::sharded::ShardedKvStore;
+pub use self::stats::{ReadStats, Stats};
 
+mod access;
+mod cache;
 mod kv;
+mod locks;
+mod manifest;
+mod marker;
+mod memory;
+mod options;
+mod sharded;
+mod snapshot;
+mod stats;
+mod value_index;
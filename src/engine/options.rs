@@ -0,0 +1,340 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where a store keeps its files inside the data directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Logs and metadata side by side in the data directory.
+    Flat,
+    /// Logs under `logs/` and metadata under `meta/`.
+    Subfolders,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Flat
+    }
+}
+
+impl Layout {
+    pub(crate) fn log_dir(self, root: &Path) -> PathBuf {
+        match self {
+            Layout::Flat => root.to_path_buf(),
+            Layout::Subfolders => root.join("logs"),
+        }
+    }
+
+    pub(crate) fn meta_dir(self, root: &Path) -> PathBuf {
+        match self {
+            Layout::Flat => root.to_path_buf(),
+            Layout::Subfolders => root.join("meta"),
+        }
+    }
+}
+
+/// When writes are synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Hand every write to the OS, only `flush` syncs. Survives a crash of the process.
+    Flush,
+    /// Sync every write before acknowledging it. Survives a crash of the machine.
+    Always,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy::Flush
+    }
+}
+
+/// Order a compaction writes the live records in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionOrder {
+    /// In key order, so range scans read the compacted log sequentially.
+    KeySorted,
+    /// In the order they were written, so recently written keys stay close together.
+    ByGeneration,
+}
+
+impl Default for CompactionOrder {
+    fn default() -> Self {
+        CompactionOrder::KeySorted
+    }
+}
+
+/// Callback given the key of every entry that expired or whose tombstone got reaped.
+#[derive(Clone)]
+pub(crate) struct EvictHook(pub(crate) Arc<dyn Fn(&str) + Send + Sync>);
+
+impl fmt::Debug for EvictHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EvictHook")
+    }
+}
+
+/// Callback given the generation being replayed, the bytes replayed so far and the bytes
+/// to replay in total.
+#[derive(Clone)]
+pub(crate) struct ProgressHook(pub(crate) Arc<dyn Fn(u64, u64, u64) + Send + Sync>);
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ProgressHook")
+    }
+}
+
+/// Options used by `KvStore::open_with_options`.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) value_cache_bytes: u64,
+    pub(crate) layout: Layout,
+    pub(crate) log_dir: Option<PathBuf>,
+    pub(crate) max_generations: Option<usize>,
+    pub(crate) max_value_bytes: Option<usize>,
+    pub(crate) format_tags: Vec<(String, u8)>,
+    pub(crate) compaction_ratio: Option<f64>,
+    pub(crate) compaction_cooldown: Option<Duration>,
+    pub(crate) max_retained_generations: Option<usize>,
+    pub(crate) reap_interval: Option<Duration>,
+    pub(crate) lenient_reads: bool,
+    pub(crate) read_repair: bool,
+    pub(crate) value_prefix_index: Option<usize>,
+    pub(crate) track_access: Option<u64>,
+    pub(crate) tombstone_retention: Option<Duration>,
+    pub(crate) dedup_sets: bool,
+    pub(crate) manifest: bool,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) on_evict: Option<EvictHook>,
+    pub(crate) on_replay_progress: Option<ProgressHook>,
+    pub(crate) compaction_threads: usize,
+    pub(crate) compaction_order: CompactionOrder,
+    pub(crate) maintenance_threads: usize,
+    pub(crate) index_snapshot: bool,
+    pub(crate) read_only: bool,
+    pub(crate) lock: bool,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            value_cache_bytes: 0,
+            layout: Layout::default(),
+            log_dir: None,
+            max_generations: None,
+            max_value_bytes: None,
+            format_tags: Vec::new(),
+            compaction_ratio: None,
+            compaction_cooldown: None,
+            max_retained_generations: None,
+            reap_interval: None,
+            lenient_reads: false,
+            read_repair: false,
+            value_prefix_index: None,
+            track_access: None,
+            tombstone_retention: None,
+            dedup_sets: false,
+            manifest: false,
+            sync_policy: SyncPolicy::default(),
+            on_evict: None,
+            on_replay_progress: None,
+            compaction_threads: 0,
+            compaction_order: CompactionOrder::default(),
+            maintenance_threads: 0,
+            index_snapshot: false,
+            read_only: false,
+            lock: true,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Cache recently read values up to `bytes` in total, 0 disables the cache.
+    pub fn value_cache_bytes(mut self, bytes: u64) -> Self {
+        self.value_cache_bytes = bytes;
+        self
+    }
+
+    /// Directory layout of the store, `Layout::Flat` by default.
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Keep the logs in `dir` instead of the data directory, e.g. on a faster disk. The
+    /// metadata stays in the data directory, which records `dir` so later opens have to
+    /// pass the same one. Both directories are locked while the store is open.
+    pub fn log_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.log_dir = Some(dir.into());
+        self
+    }
+
+    /// Compact once the store has more than `n` log files, `n` is at least 2.
+    pub fn max_generations(mut self, n: usize) -> Self {
+        self.max_generations = Some(n.max(2));
+        self
+    }
+
+    /// Reject values longer than `bytes` with `KvError::ValueTooLarge`.
+    pub fn max_value_bytes(mut self, bytes: usize) -> Self {
+        self.max_value_bytes = Some(bytes);
+        self
+    }
+
+    /// Tag values of keys starting with `prefix` with `format_tag`, unless set with a tag
+    /// of their own. The longest matching prefix wins.
+    pub fn format_for_prefix(mut self, prefix: impl Into<String>, format_tag: u8) -> Self {
+        self.format_tags.push((prefix.into(), format_tag));
+        self
+    }
+
+    /// Compact once more than `ratio` of all log bytes are stale, instead of once a fixed
+    /// amount of them is. Unlike the fixed threshold this scales with the size of the store.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = Some(ratio);
+        self
+    }
+
+    /// Don't compact on its own again until `cooldown` after the last compaction, however
+    /// much garbage piles up. `KvStore::compact` ignores the cooldown.
+    pub fn compaction_cooldown(mut self, cooldown: Duration) -> Self {
+        self.compaction_cooldown = Some(cooldown);
+        self
+    }
+
+    /// Logs deleted by a compaction stay on disk while any clone of the store still has them
+    /// open, which an idle clone may do indefinitely. After each compaction, close all but
+    /// the `max` newest of them; a clone reading again reopens what it needs.
+    pub fn max_retained_generations(mut self, max: usize) -> Self {
+        self.max_retained_generations = Some(max);
+        self
+    }
+
+    /// Remove expired keys every `interval` in the background, rather than only when they
+    /// are read or compacted away.
+    pub fn reap_interval(mut self, interval: Duration) -> Self {
+        self.reap_interval = Some(interval);
+        self
+    }
+
+    /// Have `get` log and read a key as absent when its index entry turns out to point at a
+    /// removal, instead of failing with `KvError::UnexpectedCommandType`. Off by default.
+    pub fn lenient_reads(mut self, lenient: bool) -> Self {
+        self.lenient_reads = lenient;
+        self
+    }
+
+    /// Have `get` rewrite values it read from an older log into the active one, so keys
+    /// that are read keep living in recent logs. Off by default.
+    ///
+    /// Each rewrite is a write, at most one per read key and rollover of the active log,
+    /// and is skipped while another write holds the writer.
+    pub fn read_repair(mut self, repair: bool) -> Self {
+        self.read_repair = repair;
+        self
+    }
+
+    /// Index keys by the first `len` characters of their values, for
+    /// `KvStore::find_by_value_prefix`. The index is kept in memory and built by reading
+    /// every value on open.
+    pub fn value_prefix_index(mut self, len: usize) -> Self {
+        self.value_prefix_index = Some(len);
+        self
+    }
+
+    /// Count reads and writes of the most accessed keys for `KvStore::top_keys`. Only one
+    /// access in `sample` is counted, standing in for `sample` of them, to bound the
+    /// overhead. Reads of absent keys aren't counted.
+    pub fn track_access(mut self, sample: u64) -> Self {
+        self.track_access = Some(sample);
+        self
+    }
+
+    /// Remember removed keys, dropping them at the first compaction after `grace`.
+    pub fn tombstone_retention(mut self, grace: Duration) -> Self {
+        self.tombstone_retention = Some(grace);
+        self
+    }
+
+    /// Skip writing a `set` whose value equals the one already stored.
+    pub fn dedup_sets(mut self, dedup: bool) -> Self {
+        self.dedup_sets = dedup;
+        self
+    }
+
+    /// Checksum the logs on clean shutdown and verify them on the next open.
+    pub fn manifest(mut self, manifest: bool) -> Self {
+        self.manifest = manifest;
+        self
+    }
+
+    /// When writes are synced to disk, `SyncPolicy::Flush` by default.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Open without creating or changing any file, writes fail with `KvError::ReadOnly`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Lock the data directory so no other store can open it at the same time, on by default.
+    /// Another open in this process fails with `KvError::AlreadyOpen`, one in another process
+    /// with `KvError::AlreadyLocked`. Read-only stores never take the lock.
+    pub fn lock(mut self, lock: bool) -> Self {
+        self.lock = lock;
+        self
+    }
+
+    /// Save the index on clean shutdown and compaction, so the next open only replays
+    /// the records written after it.
+    pub fn index_snapshot(mut self, snapshot: bool) -> Self {
+        self.index_snapshot = snapshot;
+        self
+    }
+
+    /// Read the records to compact on `threads` threads, the compacted log is still
+    /// written by one thread. 0 or 1 reads on the compacting thread itself.
+    pub fn compaction_threads(mut self, threads: usize) -> Self {
+        self.compaction_threads = threads;
+        self
+    }
+
+    /// Order compactions write the live records in, `CompactionOrder::KeySorted` by default.
+    pub fn compaction_order(mut self, order: CompactionOrder) -> Self {
+        self.compaction_order = order;
+        self
+    }
+
+    /// Run the compactions writes trigger on a pool of `threads` threads owned by the
+    /// store, so the writing thread doesn't wait for them. 0, the default, compacts on
+    /// the writing thread. `KvStore::compact` always compacts on the calling thread.
+    pub fn maintenance_threads(mut self, threads: usize) -> Self {
+        self.maintenance_threads = threads;
+        self
+    }
+
+    /// Call `hook` with the key of every entry that expires or whose tombstone is reaped
+    /// by a compaction. The hook runs without any lock of the store held.
+    pub fn on_evict<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.on_evict = Some(EvictHook(Arc::new(hook)));
+        self
+    }
+
+    /// Call `hook(generation, bytes_read, total_bytes)` while `open` replays the logs, at
+    /// least once per log and every MiB within one. `bytes_read` reaches `total_bytes` once
+    /// the last log is replayed.
+    pub fn on_replay_progress<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(u64, u64, u64) + Send + Sync + 'static,
+    {
+        self.on_replay_progress = Some(ProgressHook(Arc::new(hook)));
+        self
+    }
+}
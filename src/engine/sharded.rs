@@ -0,0 +1,150 @@
+use super::{KeyMeta, KvEngine, KvStore, KvStoreOptions, Stats, ValueVersion};
+use crate::{KvError, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Spreads keys over several `KvStore`s, each in a `shard-<i>` subdirectory, so writes of
+/// keys in different shards don't wait for each other.
+///
+/// A key is looked up in one shard only, so a store must always be opened with the same
+/// number of shards and the same router.
+#[derive(Clone)]
+pub struct ShardedKvStore {
+    shards: Arc<[KvStore]>,
+    router: Arc<dyn Fn(&str) -> usize + Send + Sync>,
+}
+
+impl ShardedKvStore {
+    /// Opens `shards` stores under `path`, routing keys by their CRC32.
+    pub fn open(path: impl Into<PathBuf>, shards: usize) -> Result<ShardedKvStore> {
+        ShardedKvStore::open_with_router(path, shards, KvStoreOptions::default(), hash_route)
+    }
+
+    /// Opens `shards` stores under `path` with `options`, storing each key in shard
+    /// `router(key) % shards`, e.g. to keep related keys together.
+    pub fn open_with_router<F>(
+        path: impl Into<PathBuf>,
+        shards: usize,
+        options: KvStoreOptions,
+        router: F,
+    ) -> Result<ShardedKvStore>
+    where
+        F: Fn(&str) -> usize + Send + Sync + 'static,
+    {
+        if shards == 0 {
+            return Err(KvError::StringError("a store needs at least one shard".to_owned()));
+        }
+        let path = path.into();
+        let shards = (0..shards)
+            .map(|i| {
+                let shard_dir = format!("shard-{}", i);
+                let mut options = options.clone();
+                options.log_dir = options.log_dir.map(|log_dir| log_dir.join(&shard_dir));
+                KvStore::open_with_options(path.join(&shard_dir), options)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ShardedKvStore {
+            shards: shards.into(),
+            router: Arc::new(router),
+        })
+    }
+
+    /// Index of the shard `key` is stored in.
+    pub fn shard_of(&self, key: &str) -> usize {
+        (self.router)(key) % self.shards.len()
+    }
+
+    fn shard(&self, key: &str) -> &KvStore {
+        &self.shards[self.shard_of(key)]
+    }
+}
+
+fn hash_route(key: &str) -> usize {
+    crc32fast::hash(key.as_bytes()) as usize
+}
+
+impl KvEngine for ShardedKvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    fn get_ref(&self, key: String) -> Result<Option<Arc<str>>> {
+        self.shard(&key).get_ref(key)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvEngine::flush)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        self.shard(&key).append(key, suffix)
+    }
+
+    fn increment_float(&self, key: String, delta: f64) -> Result<f64> {
+        self.shard(&key).increment_float(key, delta)
+    }
+
+    /// Atomic only if both keys are in the same shard.
+    fn rename(&self, from: String, to: String) -> Result<bool> {
+        if self.shard_of(&from) == self.shard_of(&to) {
+            return self.shard(&from).rename(from, to);
+        }
+        let value = match self.get(from.clone())? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        self.set(to, value)?;
+        self.remove(from)?;
+        Ok(true)
+    }
+
+    fn remove_if_exists(&self, key: String) -> Result<bool> {
+        self.shard(&key).remove_if_exists(key)
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, ValueVersion)>> {
+        self.shard(&key).get_versioned(key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, version: ValueVersion) -> Result<bool> {
+        self.shard(&key).set_if_version(key, value, version)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            keys.extend(shard.keys()?);
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        for shard in self.shards.iter() {
+            stats.add(&shard.stats());
+        }
+        stats
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.shard(&key).ttl(key)
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.shard(&key).set_with_ttl(key, value, ttl)
+    }
+
+    fn meta(&self, key: String) -> Result<KeyMeta> {
+        self.shard(&key).meta(key)
+    }
+}
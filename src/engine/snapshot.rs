@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crossbeam_skiplist::SkipMap;
+use serde::{Deserialize, Serialize};
+
+use super::kv::{log_path, CommandIndex};
+use crate::Result;
+
+const INDEX_SNAPSHOT: &str = "INDEX";
+
+/// The index as of the moment every log had the recorded size.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct IndexSnapshot {
+    /// Version and size of every log.
+    generations: Vec<(u64, u64)>,
+    pub(super) uncompacted: u64,
+    /// Logs holding records of unknown commands.
+    #[serde(default)]
+    pub(super) unknown_logs: Vec<u64>,
+    pub(super) entries: Vec<(String, CommandIndex)>,
+    pub(super) tombstones: Vec<String>,
+}
+
+impl IndexSnapshot {
+    /// Where replay of each log has to start, `None` if the logs changed other than by
+    /// appending records or adding newer logs, so the snapshot can't be trusted.
+    pub(super) fn replay_from(
+        &self,
+        log_dir: &Path,
+        gen_list: &[u64],
+    ) -> Option<BTreeMap<u64, u64>> {
+        let mut from = BTreeMap::new();
+        for &(version, size) in &self.generations {
+            let len = fs::metadata(log_path(log_dir, version)).ok()?.len();
+            if len < size {
+                return None;
+            }
+            from.insert(version, size);
+        }
+        let last = self.generations.last().map_or(0, |&(version, _)| version);
+        if gen_list.iter().any(|gen| *gen < last && !from.contains_key(gen)) {
+            return None;
+        }
+        Some(from)
+    }
+}
+
+pub(super) fn write_snapshot<I>(
+    meta_dir: &Path,
+    generations: Vec<(u64, u64)>,
+    uncompacted: u64,
+    unknown_logs: Vec<u64>,
+    index: &SkipMap<String, CommandIndex>,
+    tombstones: I,
+) -> Result<()>
+where
+    I: Iterator<Item = String>,
+{
+    let snapshot = IndexSnapshot {
+        generations,
+        uncompacted,
+        unknown_logs,
+        entries: index
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect(),
+        tombstones: tombstones.collect(),
+    };
+    // Written aside and renamed, so a crash never leaves a partial snapshot behind
+    let tmp = meta_dir.join(format!("{}.tmp", INDEX_SNAPSHOT));
+    fs::write(&tmp, serde_json::to_vec(&snapshot)?)?;
+    fs::rename(tmp, meta_dir.join(INDEX_SNAPSHOT))?;
+    Ok(())
+}
+
+pub(super) fn read_snapshot(meta_dir: &Path) -> Result<Option<IndexSnapshot>> {
+    match fs::read(meta_dir.join(INDEX_SNAPSHOT)) {
+        Ok(content) => match serde_json::from_slice(&content) {
+            Ok(snapshot) => Ok(Some(snapshot)),
+            Err(e) => {
+                warn!("Index snapshot is invalid: {}", e);
+                Ok(None)
+            }
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+
+/// Point-in-time statistics of a `KvStore`, see `KvEngine::stats` for other engines.
+///
+/// Fields missing from the stats of an older server read as 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Stats {
+    /// Number of live keys.
+    pub keys: u64,
+    /// Number of log files.
+    pub generations: u64,
+    /// Total length of the log files.
+    pub log_bytes: u64,
+    /// Bytes a compaction could reclaim.
+    pub uncompacted: u64,
+    /// Compactions since the store was opened.
+    pub compactions: u64,
+    /// Time spent in those compactions.
+    pub compaction_millis: u64,
+    /// Bytes of logs those compactions freed.
+    pub compaction_reclaimed_bytes: u64,
+    /// Bytes of records written to the logs since the store was opened, not counting
+    /// compactions.
+    pub bytes_written: u64,
+    /// Bytes of logs written by compactions since the store was opened.
+    pub compaction_bytes_written: u64,
+    /// Number of `get`s served from the value cache.
+    pub cache_hits: u64,
+    /// Bytes of values held by the value cache.
+    pub cache_bytes: u64,
+    /// Histogram of value lengths: bucket `i` counts values of `i` significant bits,
+    /// that is empty values in bucket 0 and lengths in `[2^(i-1), 2^i)` in bucket `i`.
+    pub value_sizes: Vec<u64>,
+}
+
+impl Stats {
+    pub(crate) fn record_value_size(&mut self, len: u64) {
+        let bucket = (64 - len.leading_zeros()) as usize;
+        if self.value_sizes.len() <= bucket {
+            self.value_sizes.resize(bucket + 1, 0);
+        }
+        self.value_sizes[bucket] += 1;
+    }
+
+    /// Bytes written to disk per byte written by the user, counting compactions. `None`
+    /// before anything was written.
+    pub fn write_amplification(&self) -> Option<f64> {
+        if self.bytes_written == 0 {
+            return None;
+        }
+        let total = self.bytes_written + self.compaction_bytes_written;
+        Some(total as f64 / self.bytes_written as f64)
+    }
+
+    /// Adds up the statistics of another store, e.g. of another shard.
+    pub(crate) fn add(&mut self, other: &Stats) {
+        self.keys += other.keys;
+        self.generations += other.generations;
+        self.log_bytes += other.log_bytes;
+        self.uncompacted += other.uncompacted;
+        self.compactions += other.compactions;
+        self.compaction_millis += other.compaction_millis;
+        self.compaction_reclaimed_bytes += other.compaction_reclaimed_bytes;
+        self.bytes_written += other.bytes_written;
+        self.compaction_bytes_written += other.compaction_bytes_written;
+        self.cache_hits += other.cache_hits;
+        self.cache_bytes += other.cache_bytes;
+        if self.value_sizes.len() < other.value_sizes.len() {
+            self.value_sizes.resize(other.value_sizes.len(), 0);
+        }
+        for (count, other) in self.value_sizes.iter_mut().zip(&other.value_sizes) {
+            *count += other;
+        }
+    }
+}
+
+/// I/O done by one `KvStore::get_with_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadStats {
+    /// Bytes read from the logs, 0 if the value came from the value cache.
+    pub bytes_read: u64,
+    /// Whether the log holding the value was already open.
+    pub file_open: bool,
+}
@@ -0,0 +1,75 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+
+/// Secondary index from the first `len` characters of each value to the keys holding it,
+/// see `KvStoreOptions::value_prefix_index`.
+pub(crate) struct ValuePrefixIndex {
+    len: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    by_prefix: BTreeMap<String, BTreeSet<String>>,
+    /// Indexed prefix of every key, to find its entry again on overwrite or removal.
+    by_key: HashMap<String, String>,
+}
+
+impl ValuePrefixIndex {
+    pub(crate) fn new(len: usize) -> ValuePrefixIndex {
+        ValuePrefixIndex {
+            len,
+            inner: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn prefix<'a>(&self, value: &'a str) -> &'a str {
+        match value.char_indices().nth(self.len) {
+            Some((end, _)) => &value[..end],
+            None => value,
+        }
+    }
+
+    pub(crate) fn insert(&self, key: &str, value: &str) {
+        let prefix = self.prefix(value);
+        let mut inner = self.inner.lock().unwrap();
+        if inner.by_key.get(key).map(String::as_str) == Some(prefix) {
+            return;
+        }
+        inner.remove(key);
+        inner.by_key.insert(key.to_owned(), prefix.to_owned());
+        let keys = inner.by_prefix.entry(prefix.to_owned()).or_default();
+        keys.insert(key.to_owned());
+    }
+
+    pub(crate) fn remove(&self, key: &str) {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    /// Keys whose value may start with `prefix`, in order. Values longer than the indexed
+    /// prefix still have to be checked.
+    pub(crate) fn candidates(&self, prefix: &str) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let indexed = self.prefix(prefix);
+        let mut keys = BTreeSet::new();
+        for (value_prefix, prefix_keys) in inner.by_prefix.range(indexed.to_owned()..) {
+            if !value_prefix.starts_with(indexed) {
+                break;
+            }
+            keys.extend(prefix_keys.iter().cloned());
+        }
+        keys.into_iter().collect()
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some(prefix) = self.by_key.remove(key) {
+            let keys = self.by_prefix.get_mut(&prefix).expect("indexed prefix has no keys");
+            keys.remove(key);
+            if keys.is_empty() {
+                self.by_prefix.remove(&prefix);
+            }
+        }
+    }
+}
@@ -1,18 +1,115 @@
-use failure::Fail;
+use crate::engine::EngineKind;
+use std::error::Error;
+use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
-#[derive(Debug, Fail)]
+#[derive(Debug)]
 pub enum KvError {
-    #[fail(display = "{}", _0)]
-    Io(#[cause] io::Error),
-    #[fail(display = "{}", _0)]
-    Serde(#[cause] serde_json::Error),
-    #[fail(display = "key not found")]
+    Io(io::Error),
+    Serde(serde_json::Error),
     KeyNotFound,
-    #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
-    #[fail(display = "{}", _0)]
+    /// Anything else, including errors a server sent back to a client.
     StringError(String),
+    WrongEngine {
+        expected: EngineKind,
+        found: EngineKind,
+    },
+    RateLimited,
+    Protocol(String),
+    Corruption(String),
+    ReadOnlyFilesystem(PathBuf),
+    AlreadyLocked(PathBuf),
+    AlreadyOpen(PathBuf),
+    ReadOnly,
+    ValueTooLarge { len: usize, max: usize },
+    InvalidKey(String),
+    IndexMismatch { key: String, version: u64 },
+    /// The number of other open handles.
+    StillOpen(usize),
+    Timeout,
+    /// Log `version` was written in a newer `format` than this build reads.
+    UnsupportedFormat { version: u64, format: u32 },
+    /// Handling a request panicked, with the panic message.
+    Internal(String),
+    /// The key whose value can't be incremented.
+    NotANumber(String),
+    /// A non-blocking client's socket isn't ready, see `KvClient::set_nonblocking`.
+    WouldBlock,
+    /// The engine asked for isn't compiled into this build.
+    EngineUnavailable { name: String },
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvError::Io(e) => write!(f, "I/O error: {}", e),
+            KvError::Serde(e) => write!(f, "invalid JSON: {}", e),
+            KvError::KeyNotFound => write!(f, "key not found"),
+            KvError::UnexpectedCommandType => write!(f, "unexpected command type in the log"),
+            KvError::StringError(msg) => write!(f, "{}", msg),
+            KvError::WrongEngine { expected, found } => {
+                write!(f, "wrong engine: expected {}, found {}", expected, found)
+            }
+            KvError::RateLimited => write!(f, "rate limited"),
+            KvError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            KvError::Corruption(msg) => write!(f, "corruption: {}", msg),
+            KvError::ReadOnlyFilesystem(path) => {
+                write!(f, "{:?} is read-only, open the store with the read_only option", path)
+            }
+            KvError::AlreadyLocked(path) => write!(f, "{:?} is held by another open store", path),
+            KvError::AlreadyOpen(path) => write!(
+                f,
+                "{:?} is already open in this process, clone the open store instead",
+                path
+            ),
+            KvError::ReadOnly => write!(f, "store is opened read-only"),
+            KvError::ValueTooLarge { len, max } => {
+                write!(f, "value of {} bytes exceeds the limit of {} bytes", len, max)
+            }
+            KvError::InvalidKey(msg) => write!(f, "invalid key: {}", msg),
+            KvError::IndexMismatch { key, version } => write!(
+                f,
+                "index entry of `{}` doesn't point at its record in log {}",
+                key, version
+            ),
+            KvError::StillOpen(others) => {
+                write!(f, "store is still used by {} other handle(s)", others)
+            }
+            KvError::Timeout => write!(f, "timed out waiting for the server, reconnect"),
+            KvError::UnsupportedFormat { version, format } => write!(
+                f,
+                "log {} is in format {}, written by a newer version of simplekv",
+                version, format
+            ),
+            KvError::Internal(msg) => write!(f, "internal error: {}", msg),
+            KvError::NotANumber(key) => write!(f, "value of `{}` is not a number", key),
+            KvError::WouldBlock => write!(f, "the request would block, repeat it later"),
+            KvError::EngineUnavailable { name } => {
+                let available: Vec<_> =
+                    EngineKind::available().iter().map(ToString::to_string).collect();
+                write!(
+                    f,
+                    "engine `{}` is not available in this build, available engines: {}",
+                    name,
+                    available.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl Error for KvError {
+    // `Io` and `Serde` already print the wrapped error, so their source is whatever that
+    // error wraps instead, which keeps walking the chain from repeating messages.
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvError::Io(e) => e.source(),
+            KvError::Serde(e) => e.source(),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for KvError {
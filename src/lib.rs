@@ -1,14 +1,25 @@
 #[macro_use]
 extern crate log;
 
-pub use client::KvClient;
-pub use engine::{KvEngine, KvStore};
+pub use client::{KvClient, KvClientBuilder, KvClientPool, PooledClient};
+pub use common::{Request, Response};
+pub use engine::{
+    check_engine, detect_engine, AccessStats, BulkLoader, CloseError, CompactionOrder, EngineKind,
+    KeyMeta, KeyState, KvEngine, KvStore, KvStoreOptions, Layout, MemoryKvEngine, ReadStats,
+    ShardedKvStore, Stats, SyncPolicy, ValueVersion,
+};
 pub use error::{KvError, Result};
+pub use migrate::migrate;
 pub use server::KvServer;
+pub use version::VersionInfo;
 
 mod client;
 mod common;
+mod compress;
 mod engine;
 mod error;
+mod migrate;
+mod rate_limit;
 mod server;
 pub mod thread_pool;
+mod version;
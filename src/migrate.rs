@@ -0,0 +1,34 @@
+use crate::{KvEngine, Result};
+
+/// Copies every key of `src` into `dst`, returning how many were copied.
+///
+/// `progress` is called after each key with the number copied so far and the number of
+/// keys `src` had when the migration started. Keys removed from `src` meanwhile are
+/// skipped, keys added meanwhile may be missed.
+///
+/// Expiring keys keep the time they have left, which fails if `dst` doesn't support them.
+pub fn migrate<S, D, F>(src: &S, dst: &D, mut progress: F) -> Result<usize>
+where
+    S: KvEngine,
+    D: KvEngine,
+    F: FnMut(usize, usize),
+{
+    let keys = src.keys()?;
+    let total = keys.len();
+    let mut copied = 0;
+    for key in keys {
+        // Read before the value, so a key expiring in between is skipped rather than copied
+        // without its TTL
+        let ttl = src.ttl(key.clone())?;
+        if let Some(value) = src.get(key.clone())? {
+            match ttl {
+                Some(ttl) => dst.set_with_ttl(key, value, ttl)?,
+                None => dst.set(key, value)?,
+            }
+            copied += 1;
+            progress(copied, total);
+        }
+    }
+    info!("Migrated {} of {} keys", copied, total);
+    Ok(copied)
+}
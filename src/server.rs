@@ -1,29 +1,192 @@
 use crate::common::*;
-use crate::{KvEngine, Result};
+use crate::compress::compress;
+use crate::rate_limit::RateLimiter;
+use crate::{KvEngine, KvError, Result};
 use crate::thread_pool::ThreadPool;
+use crossbeam::channel::{self, RecvTimeoutError};
 use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::mem;
+use std::error::Error;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Default number of requests read ahead per connection, see `KvServer::max_in_flight`.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 32;
+
+/// Default number of connections read ahead at once, see `KvServer::max_read_ahead`.
+pub const DEFAULT_MAX_READ_AHEAD: usize = 64;
 
 pub struct KvServer<E: KvEngine, P: ThreadPool> {
     engine: E,
     pool: P,
+    limiter: Option<Arc<RateLimiter>>,
+    max_in_flight: usize,
+    read_ahead: Arc<ReadAheadSlots>,
+    listeners: Vec<TcpListener>,
+    metrics_interval: Option<Duration>,
+    /// Requests received on all connections.
+    requests: Arc<AtomicU64>,
 }
 
 impl<E: KvEngine, P: ThreadPool> KvServer<E, P> {
     pub fn new(engine: E, pool: P) -> Self {
-        KvServer { engine, pool}
+        KvServer {
+            engine,
+            pool,
+            limiter: None,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            read_ahead: Arc::new(ReadAheadSlots::new(DEFAULT_MAX_READ_AHEAD)),
+            listeners: Vec::new(),
+            metrics_interval: None,
+            requests: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Reads at most `n` requests of a connection ahead of the one being answered,
+    /// `DEFAULT_MAX_IN_FLIGHT` by default.
+    pub fn max_in_flight(mut self, n: usize) -> Self {
+        self.max_in_flight = n.max(1);
+        self
+    }
+
+    /// Reads requests ahead on at most `n` connections at once, each taking a thread of its
+    /// own, `DEFAULT_MAX_READ_AHEAD` by default. Further connections are served one request
+    /// at a time.
+    pub fn max_read_ahead(mut self, n: usize) -> Self {
+        self.read_ahead = Arc::new(ReadAheadSlots::new(n));
+        self
+    }
+
+    /// Limits all connections together to `ops_per_sec` requests, allowing bursts of `burst`.
+    ///
+    /// Requests over the limit are answered with `KvError::RateLimited`.
+    pub fn rate_limit(mut self, ops_per_sec: u32, burst: u32) -> Self {
+        self.limiter = Some(Arc::new(RateLimiter::new(ops_per_sec, burst)));
+        self
     }
+
+    /// Logs the engine statistics and the number of requests served every `interval`
+    /// while the server runs.
+    pub fn report_metrics(mut self, interval: Duration) -> Self {
+        self.metrics_interval = Some(interval);
+        self
+    }
+
+    /// Starts listening on `addr` right away, connections are accepted once `serve` runs.
+    /// If `addr` resolves to several addresses, like a hostname may, the first one that can
+    /// be bound is used.
+    ///
+    /// Can be called repeatedly to listen on several addresses.
+    pub fn bind<A: ToSocketAddrs>(mut self, addr: A) -> Result<Self> {
+        self.listeners.push(TcpListener::bind(addr)?);
+        Ok(self)
+    }
+
+    /// The address the server was first bound to, with the actual port if bound to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match self.listeners.first() {
+            Some(listener) => Ok(listener.local_addr()?),
+            None => Err(KvError::StringError("server is not bound".to_owned())),
+        }
+    }
+
+    /// Every address the server was bound to, in the order of the `bind` calls.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(self.listeners.iter().map(TcpListener::local_addr).collect::<io::Result<_>>()?)
+    }
+
     pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            let engine = self.engine.clone();
-            self.pool.spawn(move ||match stream {
-                Ok(stream) => {
-                    if let Err(e) = serve(engine, stream) {
-                        error!("Error on serving client: {}", e);
+        self.bind(addr)?.serve()
+    }
+
+    /// Like `run`, but listens on every address in `addrs`.
+    pub fn run_multi(mut self, addrs: &[SocketAddr]) -> Result<()> {
+        for addr in addrs {
+            self = self.bind(addr)?;
+        }
+        self.serve()
+    }
+
+    /// Serves a single connection read from `reader` and answered on `writer` until the
+    /// reader hits EOF, e.g. to drive the server from in-memory buffers in tests.
+    pub fn serve_connection<R, W>(&self, reader: R, writer: W) -> Result<()>
+    where
+        R: Read + Send + 'static,
+        W: Write,
+    {
+        serve_connection(
+            self.engine.clone(),
+            reader,
+            writer,
+            "in-process client".to_owned(),
+            self.limits(),
+            &self.requests,
+        )
+    }
+
+    fn limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            limiter: self.limiter.clone(),
+            max_in_flight: self.max_in_flight,
+            read_ahead: Arc::clone(&self.read_ahead),
+        }
+    }
+
+    /// Serves connections on every address given to `bind`.
+    pub fn serve(mut self) -> Result<()> {
+        let listeners = mem::replace(&mut self.listeners, Vec::new());
+        if listeners.is_empty() {
+            return Err(KvError::StringError("server is not bound".to_owned()));
+        }
+        self.serve_listeners(listeners)
+    }
+
+    fn serve_listeners(self, listeners: Vec<TcpListener>) -> Result<()> {
+        // Every listener accepts on its own thread, the connections are all served by the pool
+        let (tx, rx) = channel::unbounded();
+        for listener in listeners {
+            let tx = tx.clone();
+            thread::Builder::new().spawn(move || {
+                for stream in listener.incoming() {
+                    if tx.send(stream).is_err() {
+                        break;
                     }
                 }
+            })?;
+        }
+        drop(tx);
+
+        // The reporter stops once `_stop_reporter` is dropped, whichever way we return
+        let (_stop_reporter, stop) = channel::bounded::<()>(0);
+        if let Some(interval) = self.metrics_interval {
+            let engine = self.engine.clone();
+            let requests = Arc::clone(&self.requests);
+            thread::Builder::new()
+                .name("kv-metrics".to_owned())
+                .spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+                        report_metrics(&engine, requests.load(Ordering::SeqCst));
+                    }
+                })?;
+        }
+
+        for stream in rx {
+            let engine = self.engine.clone();
+            let limits = self.limits();
+            let requests = Arc::clone(&self.requests);
+            self.pool.spawn(move ||match stream {
+                Ok(stream) => match serve(engine, stream, limits, requests) {
+                    Err(ref e) if is_disconnect(e) => debug!("Client disconnected: {}", e),
+                    Err(e) => error!("Error on serving client: {}", e),
+                    Ok(()) => {}
+                },
                 Err(e) => error!("Connection failed: {}", e),
             });
         }
@@ -31,38 +194,332 @@ impl<E: KvEngine, P: ThreadPool> KvServer<E, P> {
     }
 }
 
-fn serve<E: KvEngine>(engine: E, tcp: TcpStream) -> Result<()> {
-    let peer_addr = tcp.peer_addr()?;
-    let reader = BufReader::new(&tcp);
-    let mut writer = BufWriter::new(&tcp);
-    let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+/// Refuses to send a value longer than the client asked for in its hello.
+fn check_value_len(len: usize, max: Option<usize>) -> Result<()> {
+    match max {
+        Some(max) if len > max => Err(KvError::ValueTooLarge { len, max }),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `e` just means the client went away, which isn't worth more than a debug log.
+fn is_disconnect(e: &KvError) -> bool {
+    let io_err = match e {
+        KvError::Io(e) => Some(e),
+        KvError::Serde(e) => e.source().and_then(|e| e.downcast_ref::<io::Error>()),
+        _ => None,
+    };
+    match io_err.map(io::Error::kind) {
+        Some(io::ErrorKind::BrokenPipe)
+        | Some(io::ErrorKind::ConnectionReset)
+        | Some(io::ErrorKind::ConnectionAborted)
+        | Some(io::ErrorKind::NotConnected) => true,
+        _ => false,
+    }
+}
+
+fn report_metrics<E: KvEngine>(engine: &E, requests: u64) {
+    let stats = engine.stats();
+    info!(
+        "Metrics: keys={} generations={} log_bytes={} uncompacted={} requests={}",
+        stats.keys, stats.generations, stats.log_bytes, stats.uncompacted, requests
+    );
+}
+
+/// Requests issued on one connection by type, logged when the connection closes.
+struct RequestCounts {
+    peer_addr: String,
+    counts: BTreeMap<&'static str, u64>,
+}
+
+impl Drop for RequestCounts {
+    fn drop(&mut self) {
+        let total: u64 = self.counts.values().sum();
+        let summary = self
+            .counts
+            .iter()
+            .map(|(name, count)| format!("{}={}", name, count))
+            .collect::<Vec<_>>()
+            .join(" ");
+        info!(
+            "Connection from {} closed after {} requests: {}",
+            self.peer_addr, total, summary
+        );
+    }
+}
+
+fn serve<E: KvEngine>(
+    engine: E,
+    tcp: TcpStream,
+    limits: ConnectionLimits,
+    requests: Arc<AtomicU64>,
+) -> Result<()> {
+    let reader = tcp.try_clone()?;
+    let peer_addr = tcp.peer_addr()?.to_string();
+    let res = serve_connection(engine, reader, &tcp, peer_addr, limits, &requests);
+    // Unblocks the reader if we stopped before the client closed the connection
+    let _ = tcp.shutdown(Shutdown::Both);
+    res
+}
+
+fn serve_connection<E, R, W>(
+    engine: E,
+    reader: R,
+    writer: W,
+    peer_addr: String,
+    limits: ConnectionLimits,
+    requests: &AtomicU64,
+) -> Result<()>
+where
+    E: KvEngine,
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let ConnectionLimits { limiter, max_in_flight, read_ahead } = limits;
+    let reader = BufReader::new(reader);
+    let slot = match read_ahead.acquire() {
+        Some(slot) => slot,
+        None => {
+            debug!("Too many connections read ahead, serving {} in order", peer_addr);
+            let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+            return serve_requests(engine, writer, peer_addr, req_reader, limiter, requests);
+        }
+    };
+    // Requests are read ahead on their own thread, but only up to `max_in_flight` of them.
+    // Past that the reader blocks and stops reading the socket until responses drain.
+    let (tx, rx) = channel::bounded(max_in_flight);
+    thread::Builder::new()
+        .name("kv-read-ahead".to_owned())
+        .spawn(move || {
+            let _slot = slot;
+            for req in Deserializer::from_reader(reader).into_iter::<Request>() {
+                if tx.send(req).is_err() {
+                    break;
+                }
+            }
+        })?;
+    serve_requests(engine, writer, peer_addr, rx, limiter, requests)
+}
+
+/// Limits the server puts on every connection.
+struct ConnectionLimits {
+    limiter: Option<Arc<RateLimiter>>,
+    max_in_flight: usize,
+    read_ahead: Arc<ReadAheadSlots>,
+}
+
+/// Counts the threads reading connections ahead, see `KvServer::max_read_ahead`.
+struct ReadAheadSlots {
+    used: AtomicUsize,
+    max: usize,
+}
+
+/// Frees its slot when the read-ahead thread holding it exits.
+struct ReadAheadSlot(Arc<ReadAheadSlots>);
+
+impl ReadAheadSlots {
+    fn new(max: usize) -> ReadAheadSlots {
+        ReadAheadSlots {
+            used: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    fn acquire(self: &Arc<Self>) -> Option<ReadAheadSlot> {
+        let max = self.max;
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                if used < max {
+                    Some(used + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| ReadAheadSlot(Arc::clone(self)))
+    }
+}
+
+impl Drop for ReadAheadSlot {
+    fn drop(&mut self) {
+        self.0.used.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve_requests<E, W, I>(
+    engine: E,
+    writer: W,
+    peer_addr: String,
+    req_reader: I,
+    limiter: Option<Arc<RateLimiter>>,
+    requests: &AtomicU64,
+) -> Result<()>
+where
+    E: KvEngine,
+    W: Write,
+    I: IntoIterator<Item = serde_json::Result<Request>>,
+{
+    let mut counts = RequestCounts {
+        peer_addr: peer_addr.clone(),
+        counts: BTreeMap::new(),
+    };
+    let mut writer = BufWriter::new(writer);
+    let mut compress_min_bytes = None;
+    let mut max_value_bytes = None;
 
     macro_rules! send_resp {
         ($resp:expr) => {{
             let resp = $resp;
-            serde_json::to_writer(&mut writer, &resp)?;
+            let bytes = serde_json::to_vec(&resp)?;
+            match compress_min_bytes {
+                Some(min) if bytes.len() >= min => serde_json::to_writer(
+                    &mut writer,
+                    &CompressedResponse::Compressed(compress(&bytes)),
+                )?,
+                _ => writer.write_all(&bytes)?,
+            }
             writer.flush()?;
             debug!("Response sent to {}: {:?}", peer_addr, resp);
         };};
     }
 
     for req in req_reader {
-        let req = req?;
-        debug!("Receive request from {}: {:?}", peer_addr, req);
-        match req {
-            Request::Get { key } => send_resp!(match engine.get(key) {
-                Ok(value) => GetResponse::Ok(value),
-                Err(e) => GetResponse::Err(format!("{}", e)),
-            }),
-            Request::Set { key, value } => send_resp!(match engine.set(key, value) {
-                Ok(_) => SetResponse::Ok(()),
-                Err(e) => SetResponse::Err(format!("{}", e)),
-            }),
-            Request::Remove { key } => send_resp!(match engine.remove(key) {
-                Ok(_) => RemoveResponse::Ok(()),
-                Err(e) => RemoveResponse::Err(format!("{}", e)),
-            }),
+        let req = match req {
+            Ok(req) => req,
+            // The stream can't be resynced after garbage, so reply and close the connection
+            Err(e) if e.is_syntax() || e.is_data() => {
+                let e = KvError::Protocol(e.to_string());
+                warn!("Malformed request from {}: {}", peer_addr, e);
+                send_resp!(ErrorResponse::Err(format!("{}", e)));
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
         };
+        debug!("Receive request from {}: {:?}", peer_addr, req);
+        *counts.counts.entry(req.name()).or_insert(0) += 1;
+        requests.fetch_add(1, Ordering::SeqCst);
+        if let Some(limiter) = &limiter {
+            if !limiter.try_acquire() {
+                send_resp!(ErrorResponse::Err(format!("{}", KvError::RateLimited)));
+                continue;
+            }
+        }
+        // A panicking handler may leave the engine or the response half written, so the
+        // client gets an error and the connection is closed instead of being served further.
+        let handled = panic::catch_unwind(AssertUnwindSafe(|| -> Result<()> {
+            match req {
+                Request::Get { key } => {
+                    let res = engine.get(key).and_then(|value| {
+                        let len = value.as_ref().map_or(0, String::len);
+                        check_value_len(len, max_value_bytes).map(|_| value)
+                    });
+                    send_resp!(match res {
+                        Ok(value) => GetResponse::Ok(value),
+                        Err(e) => GetResponse::Err(format!("{}", e)),
+                    })
+                }
+                Request::Set { key, value } => send_resp!(match engine.set(key, value) {
+                    Ok(_) => SetResponse::Ok(()),
+                    Err(e) => SetResponse::Err(format!("{}", e)),
+                }),
+                Request::GetVersioned { key } => {
+                    let res = engine.get_versioned(key).and_then(|versioned| {
+                        let len = versioned.as_ref().map_or(0, |(value, _)| value.len());
+                        check_value_len(len, max_value_bytes).map(|_| versioned)
+                    });
+                    send_resp!(match res {
+                        Ok(versioned) => GetVersionedResponse::Ok(versioned),
+                        Err(e) => GetVersionedResponse::Err(format!("{}", e)),
+                    })
+                }
+                Request::SetIfVersion { key, value, version } => {
+                    send_resp!(match engine.set_if_version(key, value, version) {
+                        Ok(set) => SetIfVersionResponse::Ok(set),
+                        Err(e) => SetIfVersionResponse::Err(format!("{}", e)),
+                    })
+                }
+                Request::BatchSet { pairs, atomic } => {
+                    send_resp!(match engine.set_many(pairs, atomic) {
+                        Ok(()) => BatchSetResponse::Ok(()),
+                        Err(e) => BatchSetResponse::Err(format!("{}", e)),
+                    })
+                }
+                Request::Remove { key } => send_resp!(match engine.remove(key) {
+                    Ok(_) => RemoveResponse::Ok(()),
+                    Err(e) => RemoveResponse::Err(format!("{}", e)),
+                }),
+                Request::RemoveIfExists { key } => send_resp!(match engine.remove_if_exists(key) {
+                    Ok(removed) => RemoveIfExistsResponse::Ok(removed),
+                    Err(e) => RemoveIfExistsResponse::Err(format!("{}", e)),
+                }),
+                Request::Append { key, suffix } => send_resp!(match engine.append(key, suffix) {
+                    Ok(len) => AppendResponse::Ok(len),
+                    Err(e) => AppendResponse::Err(format!("{}", e)),
+                }),
+                Request::Rename { from, to } => send_resp!(match engine.rename(from, to) {
+                    Ok(existed) => RenameResponse::Ok(existed),
+                    Err(e) => RenameResponse::Err(format!("{}", e)),
+                }),
+                Request::IncrFloat { key, delta } => {
+                    send_resp!(match engine.increment_float(key, delta) {
+                        Ok(value) => IncrFloatResponse::Ok(value),
+                        Err(e) => IncrFloatResponse::Err(format!("{}", e)),
+                    })
+                }
+                Request::MultiRemove { keys } => send_resp!(match engine.remove_many(keys) {
+                    Ok(removed) => MultiRemoveResponse::Ok(removed),
+                    Err(e) => MultiRemoveResponse::Err(format!("{}", e)),
+                }),
+                Request::Meta { key } => send_resp!(match engine.meta(key) {
+                    Ok(meta) => MetaResponse::Ok(meta),
+                    Err(e) => MetaResponse::Err(format!("{}", e)),
+                }),
+                Request::Scan { prefix } => {
+                    let res = engine.scan(prefix).and_then(|pairs| {
+                        for (_, value) in &pairs {
+                            check_value_len(value.len(), max_value_bytes)?;
+                        }
+                        Ok(pairs)
+                    });
+                    send_resp!(match res {
+                        Ok(pairs) => ScanResponse::Ok(pairs),
+                        Err(e) => ScanResponse::Err(format!("{}", e)),
+                    })
+                }
+                Request::Hello { compress_min_bytes: min, max_value_bytes: max } => {
+                    compress_min_bytes = min;
+                    max_value_bytes = max;
+                    send_resp!(HelloResponse::Ok(()))
+                }
+                Request::Ttl { key } => send_resp!(match engine.ttl(key) {
+                    Ok(ttl) => TtlResponse::Ok(ttl),
+                    Err(e) => TtlResponse::Err(format!("{}", e)),
+                }),
+                Request::Sync => send_resp!(match engine.flush() {
+                    Ok(()) => SyncResponse::Ok(()),
+                    Err(e) => SyncResponse::Err(format!("{}", e)),
+                }),
+                Request::Stats => send_resp!(StatsResponse::Ok(engine.stats())),
+            };
+            Ok(())
+        }));
+        match handled {
+            Ok(res) => res?,
+            Err(payload) => {
+                let e = internal_error(&*payload);
+                error!("Request from {} panicked: {}", peer_addr, e);
+                send_resp!(ErrorResponse::Err(format!("{}", e)));
+                return Ok(());
+            }
+        }
     }
     Ok(())
+}
+
+fn internal_error(payload: &(dyn Any + Send)) -> KvError {
+    let msg = match payload.downcast_ref::<&str>() {
+        Some(msg) => msg.to_string(),
+        None => payload.downcast_ref::<String>().cloned().unwrap_or_default(),
+    };
+    KvError::Internal(msg)
 }
\ No newline at end of file
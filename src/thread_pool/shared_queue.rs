@@ -3,14 +3,23 @@ use std::thread;
 use crossbeam::channel::{self, Receiver, Sender};
 use super::ThreadPool;
 
+const DEFAULT_NAME_PREFIX: &str = "kv-worker";
+
 #[derive(Clone)]
-struct TaskReceiver(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+struct TaskReceiver {
+    rx: Receiver<Box<dyn FnOnce() + Send + 'static>>,
+    /// Name of the worker, kept for the replacement of a panicked worker.
+    name: String,
+}
 
 impl Drop for TaskReceiver {
     fn drop(&mut self) {
         if thread::panicking() {
             let rx = self.clone();
-            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(rx)) {
+            if let Err(e) = thread::Builder::new()
+                .name(self.name.clone())
+                .spawn(move || run_tasks(rx))
+            {
                 error!("Failed to spawn a thread: {}", e);
             }
         }
@@ -19,11 +28,14 @@ impl Drop for TaskReceiver {
 
 fn run_tasks(rx: TaskReceiver) {
     loop {
-        match rx.0.recv() {
+        match rx.rx.recv() {
             Ok(task) => {
                 task();
             }
-            Err(_) => error!("Thread exits because the thread pool is destroyed."),
+            Err(_) => {
+                debug!("Thread exits because the thread pool is destroyed.");
+                return;
+            }
         }
     }
 }
@@ -32,17 +44,28 @@ pub struct SharedQueueThreadPool {
     tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
 }
 
+impl SharedQueueThreadPool {
+    /// Like `new`, naming the workers `<prefix>-<n>` instead of `kv-worker-<n>`.
+    pub fn with_name_prefix(prefix: &str, n: i32) -> Result<Self> {
+        let (tx, rx) = channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
+        for i in 0..n {
+            let name = format!("{}-{}", prefix, i);
+            let rx = TaskReceiver {
+                rx: rx.clone(),
+                name: name.clone(),
+            };
+            thread::Builder::new().name(name).spawn(move || run_tasks(rx))?;
+        }
+        Ok(SharedQueueThreadPool { tx })
+    }
+}
+
 impl ThreadPool for SharedQueueThreadPool {
     fn new(n: i32) -> Result<Self>
         where
             Self: Sized,
     {
-        let (tx, rx) = channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
-        for _ in 0..n {
-            let rx = TaskReceiver(rx.clone());
-            thread::Builder::new().spawn(move || run_tasks(rx))?;
-        }
-        Ok(SharedQueueThreadPool { tx })
+        SharedQueueThreadPool::with_name_prefix(DEFAULT_NAME_PREFIX, n)
     }
 
     fn spawn<F>(&self, job: F)
@@ -0,0 +1,25 @@
+use crate::common::{Request, PROTOCOL_VERSION};
+use crate::EngineKind;
+use serde::{Deserialize, Serialize};
+
+/// Build information printed by `--version-json` of both binaries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub version: String,
+    /// Engine a server uses for a data directory without engine marker.
+    pub default_engine: String,
+    pub protocol_version: u32,
+    /// Requests the server understands.
+    pub features: Vec<String>,
+}
+
+impl VersionInfo {
+    pub fn current() -> VersionInfo {
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            default_engine: EngineKind::KvStore.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            features: Request::NAMES.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+}
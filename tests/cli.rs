@@ -1,7 +1,10 @@
 use assert_cmd::prelude::*;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
+use simplekv::{detect_engine, KvClient, KvEngine, KvStore, Stats, VersionInfo};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::Read;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -118,6 +121,39 @@ fn client_cli_invalid_rm() {
         .failure();
 }
 
+// The client should keep retrying until the server comes up.
+#[test]
+fn client_cli_retry() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4005";
+    let mut client = Command::cargo_bin("kv-client").unwrap();
+    client
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(&["--retry", "10", "--retry-interval", "100"])
+        .current_dir(&temp_dir);
+    let handle = thread::spawn(move || client.output().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvstore", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let output = handle.join().unwrap();
+    child.kill().expect("server exited before killed");
+    assert!(output.status.success());
+
+    Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4006"])
+        .args(&["--retry", "1", "--retry-interval", "10"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("failed to connect"));
+}
+
 #[test]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
@@ -151,6 +187,21 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kv-server --engine sled` fails naming the engines this build has
+#[test]
+fn server_cli_unavailable_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kv-server").unwrap();
+    cmd.args(&["--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("engine `sled` is not available").and(contains("engines: kvstore")))
+        .stderr(contains("Listening on").not());
+    // Nothing was written for the engine
+    assert_eq!(detect_engine(temp_dir.path()).unwrap(), None);
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -226,6 +277,22 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(contains("key not found"));
 
+    Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(&["get", "key2", "--default", "none", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("none\n");
+
+    Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(&["get", "key1", "--default", "none", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+
     Command::cargo_bin("kv-client")
         .unwrap()
         .args(&["rm", "key2", "--addr", addr])
@@ -285,7 +352,186 @@ fn cli_access_server(engine: &str, addr: &str) {
     handle.join().unwrap();
 }
 
+#[test]
+fn server_cli_multiple_addresses() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4007", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn server_cli_logs_request_counts() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvClient::connect("127.0.0.1:4009").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+    drop(client);
+    thread::sleep(Duration::from_millis(500));
+    child.kill().expect("server exited before killed");
+
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+    assert!(
+        stderr.contains("closed after 3 requests: get=1 set=2"),
+        "unexpected server log: {}",
+        stderr
+    );
+}
+
 #[test]
 fn cli_access_server_kv_engine() {
     cli_access_server("kvstore", "127.0.0.1:4004");
 }
+
+// Writes synced by the client survive the server getting killed.
+#[test]
+fn client_sync_survives_kill() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvClient::connect("127.0.0.1:4010").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.sync().unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+#[test]
+fn cli_version_json() {
+    for bin in &["kv-client", "kv-server"] {
+        let temp_dir = TempDir::new().unwrap();
+        let output = Command::cargo_bin(bin)
+            .unwrap()
+            .args(&["--version-json"])
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let info: VersionInfo = serde_json::from_slice(&output.stdout).unwrap();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.default_engine, "kvstore");
+        assert!(info.features.iter().any(|feature| feature == "get"));
+    }
+}
+
+#[test]
+fn server_cli_reports_metrics() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4011", "--metrics-interval", "200"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let mut client = KvClient::connect("127.0.0.1:4011").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(500));
+    child.kill().expect("server exited before killed");
+
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+    assert!(stderr.contains("Metrics: keys=0"), "unexpected server log: {}", stderr);
+    assert!(stderr.contains("Metrics: keys=1"), "unexpected server log: {}", stderr);
+    assert!(stderr.contains("requests=1"), "unexpected server log: {}", stderr);
+}
+
+#[test]
+fn client_cli_stats_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4012"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvClient::connect("127.0.0.1:4012").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+
+    let output = Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(&["stats", "--format", "json", "--addr", "127.0.0.1:4012"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    child.kill().expect("server exited before killed");
+    assert!(output.status.success());
+    let stats: Stats = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.generations, 1);
+    assert!(stats.uncompacted > 0);
+    assert!(stats.log_bytes > stats.uncompacted);
+}
+
+#[test]
+fn server_cli_warns_on_empty_active_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+    // Left by a crash right after the active log was created, and some unrelated entry
+    fs::write(temp_dir.path().join("100.log"), "").unwrap();
+    fs::create_dir(temp_dir.path().join("101.log")).unwrap();
+
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4013"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let mut client = KvClient::connect("127.0.0.1:4013").unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    child.kill().expect("server exited before killed");
+
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+    assert!(stderr.contains("100.log\" is empty"), "unexpected server log: {}", stderr);
+    assert!(temp_dir.path().join("102.log").is_file());
+}
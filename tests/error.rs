@@ -0,0 +1,71 @@
+use simplekv::{EngineKind, KvError};
+use std::collections::HashSet;
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+
+fn all_errors() -> Vec<KvError> {
+    vec![
+        KvError::Io(io::Error::new(io::ErrorKind::Other, "disk on fire")),
+        KvError::Serde(serde_json::from_str::<u64>("{").unwrap_err()),
+        KvError::KeyNotFound,
+        KvError::UnexpectedCommandType,
+        KvError::StringError("server went away".to_owned()),
+        KvError::WrongEngine {
+            expected: EngineKind::KvStore,
+            found: EngineKind::Sled,
+        },
+        KvError::RateLimited,
+        KvError::Protocol("unknown request".to_owned()),
+        KvError::Corruption("bad checksum".to_owned()),
+        KvError::ReadOnlyFilesystem(PathBuf::from("/data")),
+        KvError::AlreadyLocked(PathBuf::from("/data")),
+        KvError::AlreadyOpen(PathBuf::from("/data")),
+        KvError::ReadOnly,
+        KvError::ValueTooLarge { len: 10, max: 5 },
+        KvError::InvalidKey("empty key".to_owned()),
+        KvError::IndexMismatch {
+            key: "key1".to_owned(),
+            version: 3,
+        },
+        KvError::StillOpen(1),
+        KvError::Timeout,
+        KvError::UnsupportedFormat {
+            version: 3,
+            format: 7,
+        },
+        KvError::Internal("index out of bounds".to_owned()),
+        KvError::NotANumber("key1".to_owned()),
+        KvError::WouldBlock,
+        KvError::EngineUnavailable {
+            name: "sled".to_owned(),
+        },
+    ]
+}
+
+#[test]
+fn messages_are_distinct() {
+    let errors = all_errors();
+    let messages: HashSet<String> = errors.iter().map(ToString::to_string).collect();
+    assert_eq!(messages.len(), errors.len());
+    for msg in &messages {
+        assert!(msg.len() > 5, "undescriptive message: {}", msg);
+    }
+    assert_eq!(errors[0].to_string(), "I/O error: disk on fire");
+    assert!(errors[1].to_string().starts_with("invalid JSON: "));
+    assert_eq!(errors[4].to_string(), "server went away");
+}
+
+// Walking the source chain never prints the same message twice
+#[test]
+fn sources_are_not_repeated() {
+    let inner = io::Error::new(io::ErrorKind::Other, "disk on fire");
+    let wrapped = KvError::Io(io::Error::new(io::ErrorKind::Other, KvError::Io(inner)));
+    let mut chain = vec![wrapped.to_string()];
+    let mut source = wrapped.source();
+    while let Some(e) = source {
+        chain.push(e.to_string());
+        source = e.source();
+    }
+    assert_eq!(chain, ["I/O error: I/O error: disk on fire"]);
+}
@@ -1,4 +1,14 @@
-use simplekv::{KvEngine, KvStore, Result};
+use simplekv::{
+    check_engine, detect_engine, AccessStats, CompactionOrder, EngineKind, KeyState, KvEngine,
+    KvError, KvStore, KvStoreOptions, Layout, ReadStats, Result, Stats, SyncPolicy,
+};
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -124,3 +134,1707 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Second read of a key should be served from the value cache, and an overwrite
+// must never be hidden by a cached value.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().value_cache_bytes(1024);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.cache_hits(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.cache_hits(), 1);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.cache_hits(), 2);
+
+    Ok(())
+}
+
+// Removing a key frees its cached value.
+#[test]
+fn remove_frees_cached_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().value_cache_bytes(1024);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.stats().cache_bytes, 6);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.stats().cache_bytes, 0);
+    let hits = store.cache_hits();
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.cache_hits(), hits);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.stats().cache_bytes, 0);
+    Ok(())
+}
+
+// `get_uncached` reads the log even when the cache holds a stale value.
+#[test]
+fn get_uncached() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().value_cache_bytes(1024);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Change the record behind the store's back, only the cache still has the old value
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("log".as_ref()))
+        .unwrap();
+    let content = fs::read_to_string(&log)?.replace("value1", "value2");
+    fs::write(&log, content)?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let hits = store.cache_hits();
+    assert_eq!(store.get_uncached("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.cache_hits(), hits);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// With the subfolder layout all logs should be written under `logs/`.
+#[test]
+fn subfolder_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().layout(Layout::Subfolders);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let logs = |dir: &std::path::Path| {
+        WalkDir::new(dir)
+            .max_depth(1)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    assert!(logs(&temp_dir.path().join("logs")) > 0);
+    assert_eq!(logs(temp_dir.path()), 0);
+    assert!(temp_dir.path().join("meta").is_dir());
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Opening a kvstore directory as another engine should fail with a typed error.
+#[test]
+fn wrong_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    match check_engine(temp_dir.path(), EngineKind::Sled) {
+        Err(KvError::WrongEngine { expected, found }) => {
+            assert_eq!(expected, EngineKind::Sled);
+            assert_eq!(found, EngineKind::KvStore);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    check_engine(temp_dir.path(), EngineKind::KvStore)?;
+
+    Ok(())
+}
+
+#[test]
+fn detect_engine_kind() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(detect_engine(temp_dir.path())?, None);
+
+    KvStore::open(temp_dir.path())?;
+    assert_eq!(detect_engine(temp_dir.path())?, Some(EngineKind::KvStore));
+
+    let subfolders = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open_with_options(
+        subfolders.path(),
+        KvStoreOptions::default().layout(Layout::Subfolders),
+    )?;
+    assert_eq!(detect_engine(subfolders.path())?, Some(EngineKind::KvStore));
+
+    Ok(())
+}
+
+// A new log goes above every generation in the directory, gaps and leftovers included.
+#[test]
+fn initial_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::rename(temp_dir.path().join("1.log"), temp_dir.path().join("3.log"))?;
+    fs::write(temp_dir.path().join("5.log"), "")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    assert!(!temp_dir.path().join("4.log").exists());
+    assert!(fs::metadata(temp_dir.path().join("6.log"))?.len() > 0);
+    assert_eq!(fs::metadata(temp_dir.path().join("5.log"))?.len(), 0);
+    drop(store);
+
+    fs::write(temp_dir.path().join("7.log.tmp"), "")?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+    assert!(temp_dir.path().join("8.log").exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("c".to_owned(), "3".to_owned())?;
+
+    assert!(store.rename("a".to_owned(), "b".to_owned())?);
+    assert_eq!(store.get("b".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert!(!store.rename("a".to_owned(), "b".to_owned())?);
+    assert!(store.rename("b".to_owned(), "c".to_owned())?);
+    assert!(store.rename("c".to_owned(), "c".to_owned())?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?, vec!["c".to_owned()]);
+    assert_eq!(store.get("c".to_owned())?, Some("1".to_owned()));
+    Ok(())
+}
+
+// Keys expiring and reaped while they are renamed are either moved or reported absent.
+#[test]
+fn rename_expiring() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().reap_interval(Duration::from_millis(1));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for round in 0..20 {
+        for i in 0..100 {
+            let ttl = Duration::from_millis(i % 5);
+            store.set_with_ttl(format!("from{}", i), "value".to_owned(), ttl)?;
+        }
+        for i in 0..100 {
+            let to = format!("to{}-{}", round, i);
+            if store.rename(format!("from{}", i), to.clone())? {
+                assert_eq!(store.get(format!("from{}", i))?, None);
+            } else {
+                assert_eq!(store.get(to)?, None);
+            }
+        }
+    }
+    store.set("key".to_owned(), "value".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn top_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().track_access(1);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    for _ in 0..100 {
+        store.get("key3".to_owned())?;
+    }
+    store.set("key5".to_owned(), "value".to_owned())?;
+
+    let top = store.top_keys(2);
+    assert_eq!(top[0].0, "key3");
+    assert_eq!(top[0].1, AccessStats { reads: 100, writes: 1 });
+    assert_eq!(top[1].0, "key5");
+    assert_eq!(top[1].1, AccessStats { reads: 0, writes: 2 });
+    drop(store);
+
+    // Off by default
+    let store = KvStore::open(temp_dir.path())?;
+    store.get("key3".to_owned())?;
+    assert!(store.top_keys(2).is_empty());
+    Ok(())
+}
+
+// Tracking stays bounded with many keys and skips reads of absent keys.
+#[test]
+fn top_keys_bounded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().track_access(1);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("hot".to_owned(), "value".to_owned())?;
+    for i in 0..5000 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        store.get("missing".to_owned())?;
+        if i % 10 == 0 {
+            store.get("hot".to_owned())?;
+        }
+    }
+
+    let top = store.top_keys(usize::MAX);
+    assert!(top.len() <= 1024, "{}", top.len());
+    assert_eq!(top[0].0, "hot");
+    assert_eq!(top[0].1, AccessStats { reads: 500, writes: 1 });
+    assert!(top.iter().all(|(key, _)| key != "missing"));
+    Ok(())
+}
+
+#[test]
+fn separate_log_dir() -> Result<()> {
+    let data_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::default().log_dir(log_dir.path().join("logs"));
+    let store = KvStore::open_with_options(data_dir.path(), options())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    drop(store);
+
+    let logs = |dir: &TempDir| {
+        WalkDir::new(dir.path())
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    assert!(logs(&log_dir) > 0);
+    assert_eq!(logs(&data_dir), 0);
+    assert_eq!(detect_engine(data_dir.path())?, Some(EngineKind::KvStore));
+
+    let store = KvStore::open_with_options(data_dir.path(), options())?;
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+
+    // Another store can't use the same log directory
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    match KvStore::open_with_options(other_dir.path(), options()) {
+        Err(KvError::AlreadyOpen(_)) => {}
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    drop(store);
+
+    // Nor can the store be opened with its logs looked for elsewhere
+    assert!(KvStore::open(data_dir.path()).is_err());
+    let elsewhere = KvStoreOptions::default().log_dir(log_dir.path().join("other"));
+    assert!(KvStore::open_with_options(data_dir.path(), elsewhere).is_err());
+    let store = KvStore::open_with_options(data_dir.path(), options())?;
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn replay_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for round in 0..3 {
+        let store = KvStore::open(temp_dir.path())?;
+        for i in 0..1000 {
+            // Distinct keys, so nothing gets compacted
+            store.set(format!("key{}-{}", round, i), "x".repeat(1000))?;
+        }
+    }
+
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let options = {
+        let calls = Arc::clone(&calls);
+        KvStoreOptions::default().on_replay_progress(move |gen, bytes_read, total_bytes| {
+            calls.lock().unwrap().push((gen, bytes_read, total_bytes));
+        })
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key2-42".to_owned())?, Some("x".repeat(1000)));
+
+    let calls = calls.lock().unwrap();
+    let total_bytes = calls[0].2;
+    assert!(total_bytes > 3_000_000);
+    assert!(calls.iter().all(|&(_, _, total)| total == total_bytes));
+    // Reported within every log as well as after it
+    assert!(calls.len() > 3);
+    assert_eq!(calls.iter().map(|&(gen, ..)| gen).collect::<BTreeSet<_>>().len(), 3);
+    assert!(calls.windows(2).all(|w| w[0].0 <= w[1].0 && w[0].1 < w[1].1));
+    assert_eq!(calls.last().unwrap().1, total_bytes);
+    Ok(())
+}
+
+#[test]
+fn append_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.append("key1".to_owned(), "a".to_owned())?, 1);
+    assert_eq!(store.append("key1".to_owned(), "b".to_owned())?, 2);
+    assert_eq!(store.append("key1".to_owned(), "c".to_owned())?, 3);
+    assert_eq!(store.get("key1".to_owned())?, Some("abc".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn increment_float() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.increment_float("key1".to_owned(), 1.5)?, 1.5);
+    assert_eq!(store.increment_float("key1".to_owned(), 1.5)?, 3.0);
+    assert_eq!(store.get("key1".to_owned())?, Some("3".to_owned()));
+
+    store.set("key2".to_owned(), "abc".to_owned())?;
+    match store.increment_float("key2".to_owned(), 1.0) {
+        Err(KvError::NotANumber(key)) => assert_eq!(key, "key2"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("abc".to_owned()));
+
+    Ok(())
+}
+
+// Reopening creates a new generation each time, compaction should keep the
+// number of log files bounded by `max_generations`.
+#[test]
+fn max_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_generations(4);
+    let log_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    for i in 0..10 {
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert!(log_count() <= 4);
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tombstone_state() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().tombstone_retention(Duration::from_secs(3600));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_state("key1".to_owned())?, KeyState::Present("value1".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_state("key1".to_owned())?, KeyState::Tombstoned);
+    assert_eq!(store.get_state("key2".to_owned())?, KeyState::Absent);
+
+    // Tombstones within the grace period survive compaction and reopen
+    store.compact()?;
+    drop(store);
+    let options = KvStoreOptions::default().tombstone_retention(Duration::from_secs(0));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get_state("key1".to_owned())?, KeyState::Tombstoned);
+
+    store.compact()?;
+    assert_eq!(store.get_state("key1".to_owned())?, KeyState::Absent);
+
+    Ok(())
+}
+
+#[test]
+fn remove_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("c".to_owned(), "3".to_owned())?;
+
+    let removed = store.remove_many(vec!["a".to_owned(), "missing".to_owned(), "c".to_owned()])?;
+    assert_eq!(removed, vec![true, false, true]);
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, None);
+
+    Ok(())
+}
+
+// Reads racing with compactions must never fail.
+#[test]
+fn read_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || -> Result<u64> {
+                let mut reads = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+                    reads += 1;
+                }
+                Ok(reads)
+            })
+        })
+        .collect();
+
+    for iter in 0..50 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+        store.compact()?;
+    }
+    stop.store(true, Ordering::SeqCst);
+    for reader in readers {
+        assert!(reader.join().unwrap()? > 0);
+    }
+
+    Ok(())
+}
+
+// In dedup mode setting the value a key already holds shouldn't write anything.
+#[test]
+fn dedup_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().dedup_sets(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum::<u64>()
+    };
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let size = dir_size();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(dir_size(), size);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(dir_size() > size);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Flipping a byte in a log between a clean shutdown and the next open is detected.
+#[test]
+fn manifest_detects_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().manifest(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // A clean reopen verifies fine
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| fs::metadata(path).unwrap().len() > 0 && path.extension() == Some("log".as_ref()))
+        .expect("no log written");
+    let mut file = OpenOptions::new().read(true).write(true).open(&log)?;
+    let mut byte = [0];
+    file.seek(SeekFrom::Start(3))?;
+    file.read_exact(&mut byte)?;
+    file.seek(SeekFrom::Start(3))?;
+    file.write_all(&[byte[0] ^ 1])?;
+    drop(file);
+
+    match KvStore::open_with_options(temp_dir.path(), options) {
+        Err(KvError::Corruption(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    Ok(())
+}
+
+// Data flushed before a crash (simulated by leaking the store) must be there after reopen.
+#[test]
+fn flush_before_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // A crashed process releases its lock, a leaked store doesn't
+    let options = KvStoreOptions::default().lock(false);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+    std::mem::forget(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Every acknowledged write of concurrent writers must survive a crash without clean shutdown
+#[test]
+fn durable_under_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::default().sync_policy(SyncPolicy::Always).lock(false),
+    )?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<Vec<(String, Option<String>)>> {
+                let mut acked = Vec::new();
+                for k in 0..10 {
+                    let key = format!("key{}-{}", t, k);
+                    let mut last = None;
+                    for i in 0..20 {
+                        if i % 7 == 6 {
+                            store.remove(key.clone())?;
+                            last = None;
+                        } else {
+                            let value = format!("value{}", i);
+                            store.set(key.clone(), value.clone())?;
+                            last = Some(value);
+                        }
+                    }
+                    acked.push((key, last));
+                }
+                Ok(acked)
+            })
+        })
+        .collect();
+    let mut acked = Vec::new();
+    for handle in handles {
+        acked.extend(handle.join().unwrap()?);
+    }
+    std::mem::forget(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, value) in acked {
+        assert_eq!(store.get(key)?, value);
+    }
+    Ok(())
+}
+
+#[test]
+fn evict_hook_on_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    let hook_evicted = Arc::clone(&evicted);
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::default()
+            .on_evict(move |key| hook_evicted.lock().unwrap().push(key.to_owned())),
+    )?;
+
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_millis(100))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(evicted.lock().unwrap().is_empty());
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(*evicted.lock().unwrap(), vec!["key1".to_owned()]);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // The expiry was logged, the key stays gone after a reopen
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Writes reading an expired key under its lock run the hook only once that is released
+#[test]
+fn evict_hook_writes_evicted_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hook_store: Arc<Mutex<Option<KvStore>>> = Arc::default();
+    let options = {
+        let hook_store = Arc::clone(&hook_store);
+        KvStoreOptions::default().on_evict(move |key| {
+            let store = hook_store.lock().unwrap().clone();
+            if let Some(store) = store {
+                store.set(key.to_owned(), "restored".to_owned()).unwrap();
+            }
+        })
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    *hook_store.lock().unwrap() = Some(store.clone());
+
+    type Op = fn(&KvStore) -> Result<()>;
+    let ops: [Op; 4] = [
+        |store| store.append("key1".to_owned(), "suffix".to_owned()).map(drop),
+        |store| store.increment_float("key1".to_owned(), 1.5).map(drop),
+        |store| store.get_or_insert_with("key1".to_owned(), || "value2".to_owned()).map(drop),
+        |store| store.rename("key1".to_owned(), "key2".to_owned()).map(drop),
+    ];
+    for op in ops.iter().cloned() {
+        store.set_with_ttl("key1".to_owned(), "1".to_owned(), Duration::from_millis(50))?;
+        thread::sleep(Duration::from_millis(100));
+        let (sender, receiver) = mpsc::channel();
+        let op_store = store.clone();
+        thread::spawn(move || sender.send(op(&op_store)).unwrap());
+        receiver.recv_timeout(Duration::from_secs(5)).expect("deadlocked")?;
+        assert_eq!(store.get("key1".to_owned())?, Some("restored".to_owned()));
+    }
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    hook_store.lock().unwrap().take();
+    Ok(())
+}
+
+#[test]
+fn store_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.path(), temp_dir.path());
+    Ok(())
+}
+
+#[test]
+fn hot_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "updated".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    store.backup(backup_dir.path())?;
+    store.set("key2".to_owned(), "after".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key0".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(backup.get("key1".to_owned())?, None);
+    assert_eq!(backup.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(backup.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(backup.get("key100".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("after".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn parallel_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for gen in 0..4 {
+        let store = KvStore::open(temp_dir.path())?;
+        for i in (gen..10_000).step_by(4) {
+            store.set(format!("key{}", i), format!("value{}-{}", i, gen))?;
+        }
+        store.remove(format!("key{}", gen))?;
+    }
+
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::default().compaction_threads(4),
+    )?;
+    store.compact()?;
+    assert_eq!(store.stats().generations, 2);
+    for i in 0..10_000 {
+        let expected = if i < 4 {
+            None
+        } else {
+            Some(format!("value{}-{}", i, i % 4))
+        };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key9999".to_owned())?, Some("value9999-3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn read_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // The logs are opened while loading
+    let store = KvStore::open(temp_dir.path())?;
+    let (value, before) = store.get_with_stats("key1".to_owned())?;
+    assert_eq!(value, Some("value2".to_owned()));
+    assert!(before.file_open);
+    assert!(before.bytes_read > "value2".len() as u64);
+
+    // The compacted log isn't open until it is read from
+    store.compact()?;
+    let (value, after) = store.get_with_stats("key1".to_owned())?;
+    assert_eq!(value, Some("value2".to_owned()));
+    assert!(!after.file_open);
+    assert_eq!(after.bytes_read, before.bytes_read);
+    let (_, again) = store.get_with_stats("key1".to_owned())?;
+    assert_eq!(
+        again,
+        ReadStats {
+            bytes_read: before.bytes_read,
+            file_open: true,
+        }
+    );
+    Ok(())
+}
+
+// Bytes taken by the logs in `dir`
+fn log_bytes(dir: &std::path::Path) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+// `uncompacted` has to be exactly what a compaction reclaims, before and after a reopen
+#[test]
+fn uncompacted_accounting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().tombstone_retention(Duration::from_secs(3600));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for _ in 0..100 {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let uncompacted = store.stats().uncompacted;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.stats().uncompacted, uncompacted);
+    let before = log_bytes(temp_dir.path());
+    store.compact()?;
+    let carried = store.stats().uncompacted;
+    assert_eq!(before - log_bytes(temp_dir.path()), uncompacted - carried);
+    assert_eq!(carried, r#"{"Remove":{"key":"key2"}}"#.len() as u64);
+    drop(store);
+
+    // The tombstone carried over by the compaction is still counted after a reopen
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats().uncompacted, carried);
+    Ok(())
+}
+
+#[test]
+fn index_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().index_snapshot(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Garble the first record, only a replay would read it
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("log".as_ref()))
+        .unwrap();
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    file.write_all(b"#")?;
+    drop(file);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone().lock(false))?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    // Records written after the snapshot are replayed
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    std::mem::forget(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // Without the snapshot the garbled record is found
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+#[test]
+fn remove_if_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let size = log_bytes(temp_dir.path());
+    assert!(!store.remove_if_exists("key2".to_owned())?);
+    assert_eq!(log_bytes(temp_dir.path()), size);
+
+    assert!(store.remove_if_exists("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!store.remove_if_exists("key1".to_owned())?);
+    Ok(())
+}
+
+#[test]
+fn empty_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    match store.set("".to_owned(), "value".to_owned()) {
+        Err(KvError::InvalidKey(_)) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(store.append("".to_owned(), "value".to_owned()).is_err());
+    assert_eq!(store.get("".to_owned())?, None);
+
+    store.set("key1".to_owned(), "".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.append("key1".to_owned(), "a".to_owned())?, 1);
+    Ok(())
+}
+
+#[test]
+fn value_size_histogram() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, len) in &[("a", 0), ("b", 1), ("c", 3), ("d", 4), ("e", 7), ("f", 100)] {
+        store.set(key.to_string(), "x".repeat(*len))?;
+    }
+
+    let stats = store.stats();
+    assert_eq!(stats.keys, 6);
+    assert_eq!(stats.value_sizes, vec![1, 1, 1, 2, 0, 0, 0, 1]);
+    Ok(())
+}
+
+// A read-only store follows compactions done by the store writing the directory.
+#[test]
+fn read_only_after_external_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value1".to_owned())?;
+    }
+    store.flush()?;
+
+    let options = KvStoreOptions::default().read_only(true);
+    let reader = KvStore::open_with_options(temp_dir.path(), options)?;
+    // A clone has no log open yet
+    let clone = reader.clone();
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    for store in &[&reader, &clone] {
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.keys()?.len(), 100);
+    }
+    Ok(())
+}
+
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let files = |dir: &TempDir| {
+        let mut files: Vec<_> = WalkDir::new(dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files(&temp_dir);
+
+    let options = KvStoreOptions::default().read_only(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvError::ReadOnly) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(store.compact().is_err());
+    drop(store);
+    assert_eq!(files(&temp_dir), before);
+
+    // A plain open on a directory it can't write to tells to open read-only
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let set_mode = |mode| {
+            for path in &before {
+                if path.is_dir() {
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+                }
+            }
+        };
+        set_mode(0o555);
+        // root ignores the permissions
+        let writable = fs::File::create(temp_dir.path().join("probe")).is_ok();
+        let res = KvStore::open(temp_dir.path());
+        set_mode(0o755);
+        if !writable {
+            match res {
+                Err(KvError::ReadOnlyFilesystem(_)) => {}
+                res => panic!("unexpected result: {:?}", res.map(|_| ())),
+            }
+        }
+    }
+    Ok(())
+}
+
+// A small store compacts on its share of stale bytes, far below the fixed threshold.
+#[test]
+fn compaction_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for _ in 0..10 {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    assert!(store.stats().uncompacted > 0);
+    drop(store);
+
+    let options = KvStoreOptions::default().compaction_ratio(0.5);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats().uncompacted, 0);
+    assert_eq!(store.stats().generations, 2);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.stats().uncompacted > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // A large store with a small share of stale bytes doesn't compact on the fixed threshold
+    let options = KvStoreOptions::default().compaction_ratio(0.9);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let value = "x".repeat(10 * 1024);
+    for i in 0..300 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    for i in 0..150 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    assert!(store.stats().uncompacted > 1024 * 1024);
+    Ok(())
+}
+
+#[test]
+fn drain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.remove("key99".to_owned())?;
+    assert_eq!(store.len(), 99);
+
+    let mut drained = store.drain()?;
+    drained.sort();
+    let mut expected: Vec<_> = (1..99)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    expected.push(("key0".to_owned(), "new".to_owned()));
+    expected.sort();
+    assert_eq!(drained, expected);
+    assert_eq!(store.len(), 0);
+    assert!(store.is_empty());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    assert_eq!(store.drain()?, vec![]);
+    Ok(())
+}
+
+#[test]
+fn last_modified() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // A record written before the timestamps were added
+    fs::write(temp_dir.path().join("1.log"), r#"{"Set":{"key":"key0","value":"value0"}}"#)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.last_modified("key0".to_owned())?, None);
+
+    let before = SystemTime::now() - Duration::from_millis(1);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let modified = store.last_modified("key1".to_owned())?.expect("no timestamp");
+    assert!(modified >= before && modified <= SystemTime::now());
+    assert_eq!(store.last_modified("key2".to_owned())?, None);
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_modified("key1".to_owned())?, Some(modified));
+    Ok(())
+}
+
+#[test]
+fn dir_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::AlreadyOpen(_)) => {}
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    // However the path is spelled
+    fs::create_dir(temp_dir.path().join("sub"))?;
+    match KvStore::open(temp_dir.path().join("sub").join("..")) {
+        Err(KvError::AlreadyOpen(_)) => {}
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    // Reading never takes the lock
+    let options = KvStoreOptions::default().read_only(true);
+    let reader = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // The lock lives as long as any clone of the store
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Files that merely look like logs are left alone.
+#[test]
+fn ignore_malformed_log_names() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let junk = ["999999999999999999999999.log", "01.log", "+2.log", "1.log.log", "x.log"];
+    for name in &junk {
+        fs::write(temp_dir.path().join(name), r#"{"Set":{"key":"key1","value":"junk"}}"#)?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    for name in &junk {
+        assert!(temp_dir.path().join(name).exists());
+    }
+    Ok(())
+}
+
+#[test]
+fn set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_value_bytes(8);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let batch = |middle: &str| {
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), middle.to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    };
+
+    match store.set_many(batch("too long a value"), true) {
+        Err(KvError::ValueTooLarge { len: 16, max: 8 }) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(store.is_empty());
+
+    // Without `atomic` the pairs before the failing one stay applied
+    assert!(store.set_many(batch("too long a value"), false).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    store.set_many(batch("value2"), true)?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 1..=3 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn value_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .format_for_prefix("json/", 1)
+        .format_for_prefix("json/bin/", 2);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set_with_format("key1".to_owned(), r#"{"a":1}"#.to_owned(), 7)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("json/key3".to_owned(), "{}".to_owned())?;
+    store.set("json/bin/key4".to_owned(), "AAAA".to_owned())?;
+
+    assert_eq!(store.value_format("key1".to_owned())?, Some(7));
+    assert_eq!(store.get("key1".to_owned())?, Some(r#"{"a":1}"#.to_owned()));
+    assert_eq!(store.value_format("key2".to_owned())?, Some(0));
+    assert_eq!(store.value_format("json/key3".to_owned())?, Some(1));
+    assert_eq!(store.value_format("json/bin/key4".to_owned())?, Some(2));
+    assert_eq!(store.value_format("key5".to_owned())?, None);
+
+    // Appending keeps the tag, and it survives compaction and reopening
+    store.append("key1".to_owned(), " ".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.value_format("key1".to_owned())?, Some(7));
+    assert_eq!(store.get("key1".to_owned())?, Some(r#"{"a":1} "#.to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compaction_cooldown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_ratio(0.5)
+        .compaction_cooldown(Duration::from_millis(500));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for _ in 0..3 {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    assert_eq!(store.stats().uncompacted, 0);
+
+    // Over the ratio again right away, but still cooling down
+    for _ in 0..10 {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    assert!(store.stats().uncompacted > store.stats().log_bytes / 2);
+
+    thread::sleep(Duration::from_millis(600));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.stats().uncompacted, 0);
+
+    // A manual compaction doesn't wait
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.stats().uncompacted > 0);
+    store.compact()?;
+    assert_eq!(store.stats().uncompacted, 0);
+    Ok(())
+}
+
+// Records of commands this version doesn't know are skipped on open, and kept on disk
+// by refusing to compact their logs.
+#[test]
+fn skip_unknown_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "log"))
+        .max()
+        .expect("no log written");
+    let mut file = OpenOptions::new().append(true).open(log)?;
+    file.write_all(br#"{"Merge":{"key":"key1","delta":3}}"#)?;
+    file.write_all(br#"{"Set":{"key":"key2","value":"value2"}}"#)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats().uncompacted, 0);
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(store.compact().is_err());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.compact().is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    let logs = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "log"));
+    let mut content = String::new();
+    for log in logs {
+        content += &fs::read_to_string(log)?;
+    }
+    assert!(content.contains(r#"{"Merge":{"key":"key1","delta":3}}"#));
+    Ok(())
+}
+
+#[test]
+fn ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_secs(10))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let ttl = store.ttl("key1".to_owned())?.expect("key1 has a TTL");
+    assert!(ttl > Duration::from_secs(9) && ttl <= Duration::from_secs(10));
+    assert_eq!(store.ttl("key2".to_owned())?, None);
+    assert_eq!(store.ttl("key3".to_owned())?, None);
+    Ok(())
+}
+
+// Clones that stopped reading don't keep deleted logs on disk past the cap.
+#[cfg(target_os = "linux")]
+#[test]
+fn max_retained_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let deleted_logs_held = || -> usize {
+        let mut held = HashSet::new();
+        for entry in fs::read_dir("/proc/self/fd").unwrap() {
+            if let Ok(target) = fs::read_link(entry.unwrap().path()) {
+                let target = target.to_string_lossy().into_owned();
+                if target.starts_with(&*temp_dir.path().to_string_lossy())
+                    && target.ends_with(" (deleted)")
+                {
+                    held.insert(target);
+                }
+            }
+        }
+        held.len()
+    };
+
+    let options = KvStoreOptions::default().max_retained_generations(1);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    // Every clone reads the log of its time and goes idle
+    let mut idle = Vec::new();
+    for _ in 0..4 {
+        let reader = store.clone();
+        assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+        idle.push(reader);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.compact()?;
+        assert!(deleted_logs_held() <= 1);
+    }
+
+    // The idle clones still work
+    for reader in &idle {
+        assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+// Expired keys nobody reads are removed in the background and compacted away.
+#[test]
+fn reap_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let reaped = Arc::new(Mutex::new(Vec::new()));
+    let hook_reaped = Arc::clone(&reaped);
+    let options = KvStoreOptions::default()
+        .reap_interval(Duration::from_millis(50))
+        .on_evict(move |key| hook_reaped.lock().unwrap().push(key.to_owned()));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_millis(100))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.len(), 1);
+    assert_eq!(*reaped.lock().unwrap(), vec!["key1".to_owned()]);
+    assert!(store.stats().uncompacted > 0);
+
+    store.compact()?;
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "log") {
+            assert!(!fs::read_to_string(path)?.contains("key1"));
+        }
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn warm_up() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Every open starts a new log
+    for key_id in 0..3 {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+
+    let cold = store.clone();
+    let (value, stats) = cold.get_with_stats("key0".to_owned())?;
+    assert_eq!(value, Some("value0".to_owned()));
+    assert!(!stats.file_open);
+
+    let warm = store.clone();
+    warm.warm_up()?;
+    for key_id in 0..3 {
+        let (value, stats) = warm.get_with_stats(format!("key{}", key_id))?;
+        assert_eq!(value, Some(format!("value{}", key_id)));
+        assert!(stats.file_open);
+    }
+    Ok(())
+}
+
+// Keys written in reverse order end up in key order or in write order after compacting.
+#[test]
+fn compaction_order() -> Result<()> {
+    for &order in &[CompactionOrder::KeySorted, CompactionOrder::ByGeneration] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().compaction_order(order);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in (0..10).rev() {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+        store.set("key5".to_owned(), "value".to_owned())?;
+        store.compact()?;
+
+        let mut dump = Vec::new();
+        store.dump_index(&mut dump)?;
+        let mut lines: Vec<serde_json::Value> = String::from_utf8(dump)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        lines.sort_by_key(|line| line["start"].as_u64().unwrap());
+        let keys: Vec<_> = lines.iter().map(|line| line["key"].as_str().unwrap()).collect();
+        let expected = match order {
+            CompactionOrder::KeySorted => (0..10).map(|i| format!("key{}", i)).collect::<Vec<_>>(),
+            CompactionOrder::ByGeneration => (0..10)
+                .rev()
+                .filter(|&i| i != 5)
+                .chain(Some(5))
+                .map(|i| format!("key{}", i))
+                .collect(),
+        };
+        assert_eq!(keys, expected);
+        assert_eq!(store.get("key5".to_owned())?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+// Every dumped entry points at the record of its key.
+#[test]
+fn dump_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.flush()?;
+
+    let mut dump = Vec::new();
+    store.dump_index(&mut dump)?;
+    let lines: Vec<serde_json::Value> = String::from_utf8(dump)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3);
+    for (line, key) in lines.iter().zip(&["key1", "key2", "key3"]) {
+        assert_eq!(line["key"], *key);
+        let log = temp_dir.path().join(format!("{}.log", line["version"]));
+        let content = fs::read(log)?;
+        let start = line["start"].as_u64().unwrap() as usize;
+        let end = start + line["len"].as_u64().unwrap() as usize;
+        let record: serde_json::Value = serde_json::from_slice(&content[start..end]).unwrap();
+        assert_eq!(record["Set"]["key"], *key);
+    }
+    Ok(())
+}
+
+// Stats sent by a server predating the newer fields still parse.
+#[test]
+fn stats_missing_fields() {
+    let json = r#"{"keys":3,"generations":2,"log_bytes":100,"uncompacted":10,"cache_hits":1,
+        "value_sizes":[0,3]}"#;
+    let stats: Stats = serde_json::from_str(json).unwrap();
+    assert_eq!(stats.keys, 3);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.cache_bytes, 0);
+    assert_eq!(stats.write_amplification(), None);
+}
+
+#[test]
+fn write_amplification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().write_amplification(), None);
+
+    for _ in 0..10 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+    }
+    let stats = store.stats();
+    assert_eq!(stats.compaction_bytes_written, 0);
+    assert_eq!(stats.write_amplification(), Some(1.0));
+
+    // Compaction rewrites the last of the 10 values of every key
+    store.compact()?;
+    let amplification = store.stats().write_amplification().unwrap();
+    assert!(amplification > 1.05 && amplification < 1.2, "{}", amplification);
+    Ok(())
+}
+
+#[test]
+fn compaction_counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().compactions, 0);
+
+    let mut reclaimed = 0;
+    for iter in 1..=3 {
+        for _ in 0..10 {
+            store.set("key1".to_owned(), "value1".to_owned())?;
+        }
+        let log_bytes = store.stats().log_bytes;
+        store.compact()?;
+        let stats = store.stats();
+        assert_eq!(stats.compactions, iter);
+        assert!(stats.compaction_reclaimed_bytes > reclaimed);
+        assert_eq!(stats.compaction_reclaimed_bytes - reclaimed, log_bytes - stats.log_bytes);
+        reclaimed = stats.compaction_reclaimed_bytes;
+    }
+    Ok(())
+}
+
+#[test]
+fn bulk_loader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    let generations = store.stats().generations;
+
+    let mut loader = store.bulk_loader()?;
+    for key_id in 0..1000 {
+        loader.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(loader.finish()?, 1000);
+    assert_eq!(store.stats().generations, generations + 1);
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    // A loader dropped without finishing leaves nothing behind
+    let log_bytes = store.stats().log_bytes;
+    let mut loader = store.bulk_loader()?;
+    for key_id in 0..1000 {
+        loader.set(format!("dropped{}", key_id), "x".repeat(100))?;
+    }
+    drop(loader);
+    assert_eq!(store.get("dropped0".to_owned())?, None);
+    store.set("key1000".to_owned(), "value1000".to_owned())?;
+    assert!(store.stats().log_bytes < log_bytes + 100);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..=1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    assert_eq!(store.get("dropped999".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let clone = store.clone();
+    let err = clone.close().unwrap_err();
+    assert!(matches!(err.error(), KvError::StillOpen(1)));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    // Closing can be retried with the store given back
+    err.into_store().expect("store was closed").close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn read_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let options = KvStoreOptions::default().read_repair(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let old_version = store.meta("key1".to_owned())?.version;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let new_version = store.meta("key1".to_owned())?.version;
+    assert!(new_version > old_version);
+    assert_eq!(store.meta("key2".to_owned())?.version, old_version);
+
+    // Already in the active log, read again without another rewrite
+    let log_bytes = store.stats().log_bytes;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.stats().log_bytes, log_bytes);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn find_by_value_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().value_prefix_index(3);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "apple".to_owned())?;
+    store.set("key2".to_owned(), "apricot".to_owned())?;
+    store.set("key3".to_owned(), "application".to_owned())?;
+    store.set("key4".to_owned(), "banana".to_owned())?;
+    store.set("key5".to_owned(), "ap".to_owned())?;
+
+    assert_eq!(store.find_by_value_prefix("ap")?, ["key1", "key2", "key3", "key5"]);
+    assert_eq!(store.find_by_value_prefix("app")?, ["key1", "key3"]);
+    assert_eq!(store.find_by_value_prefix("appl")?, ["key1", "key3"]);
+    assert_eq!(store.find_by_value_prefix("applic")?, ["key3"]);
+    assert!(store.find_by_value_prefix("cherry")?.is_empty());
+
+    store.remove("key1".to_owned())?;
+    store.set("key3".to_owned(), "cherry".to_owned())?;
+    assert_eq!(store.find_by_value_prefix("app")?, Vec::<String>::new());
+    assert_eq!(store.find_by_value_prefix("ch")?, ["key3"]);
+    drop(store);
+
+    // Rebuilt on open
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.find_by_value_prefix("ap")?, ["key2", "key5"]);
+    assert!(KvStore::open(TempDir::new().unwrap().path())?.find_by_value_prefix("a").is_err());
+    Ok(())
+}
+
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let value = store.get_or_insert_with("key1".to_owned(), || panic!("key1 is present"))?;
+    assert_eq!(value, "value1");
+
+    let loads = Arc::new(AtomicUsize::new(0));
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let loads = Arc::clone(&loads);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                store.get_or_insert_with("key2".to_owned(), || {
+                    loads.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    format!("value{}", thread_id)
+                })
+            })
+        })
+        .collect();
+    let values: HashSet<_> = handles.into_iter().map(|h| h.join().unwrap().unwrap()).collect();
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+    assert_eq!(values.len(), 1);
+    assert_eq!(store.get("key2".to_owned())?, values.into_iter().next());
+    Ok(())
+}
+
+// Logs written before log formats had versions still open, next to newer ones.
+#[test]
+fn legacy_log_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut legacy = String::new();
+    legacy.push_str(r#"{"Set":{"key":"key1","value":"value1"}}"#);
+    legacy.push_str(r#"{"Set":{"key":"key2","value":"value2"}}"#);
+    legacy.push_str(r#"{"Remove":{"key":"key2"}}"#);
+    fs::write(temp_dir.path().join("1.log"), legacy)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let current = fs::read_to_string(temp_dir.path().join("2.log"))?;
+    assert!(current.starts_with(r#"{"Format":2}{"Set":{"key":"key3""#), "{}", current);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // A format from the future is refused rather than misread
+    fs::write(temp_dir.path().join("100.log"), r#"{"Format":3}"#)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::UnsupportedFormat { version: 100, format: 3 }) => {}
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    // and a format from before formats were numbered is corrupt
+    fs::write(temp_dir.path().join("100.log"), r#"{"Format":0}"#)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Corruption(_)) => {}
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    Ok(())
+}
+
+#[test]
+fn for_each_in_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), "x".repeat(key_id))?;
+    }
+    store.remove("key015".to_owned())?;
+
+    let mut keys = Vec::new();
+    let mut total_len = 0;
+    store.for_each_in_range("key010".to_owned().."key020".to_owned(), |key, value| {
+        keys.push(key.to_owned());
+        total_len += value.len();
+        Ok(())
+    })?;
+    assert_eq!(keys.len(), 9);
+    assert_eq!(keys.first().map(String::as_str), Some("key010"));
+    assert_eq!(keys.last().map(String::as_str), Some("key019"));
+    assert_eq!(total_len, (10..20).sum::<usize>() - 15);
+
+    // Stopped by the callback's error
+    let mut seen = 0;
+    let res = store.for_each_in_range("key090".to_owned().., |_, _| {
+        seen += 1;
+        if seen == 3 {
+            Err(KvError::StringError("enough".to_owned()))
+        } else {
+            Ok(())
+        }
+    });
+    match res {
+        Err(KvError::StringError(msg)) => assert_eq!(msg, "enough"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(seen, 3);
+    Ok(())
+}
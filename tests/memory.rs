@@ -0,0 +1,55 @@
+use simplekv::{KvEngine, KvError, MemoryKvEngine, Result};
+use std::sync::Arc;
+
+#[test]
+fn get_set_remove() -> Result<()> {
+    let engine = MemoryKvEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.append("key1".to_owned(), "2".to_owned())?, 7);
+    assert_eq!(engine.meta("key1".to_owned())?.len, 7);
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    match engine.remove("key1".to_owned()) {
+        Err(KvError::KeyNotFound) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    Ok(())
+}
+
+// `get_ref` should hand out the stored value itself, not a copy of it.
+#[test]
+fn get_ref_does_not_copy() -> Result<()> {
+    let engine = MemoryKvEngine::new();
+    engine.set("key1".to_owned(), "x".repeat(1024 * 1024))?;
+
+    let first = engine.get_ref("key1".to_owned())?.unwrap();
+    let second = engine.get_ref("key1".to_owned())?.unwrap();
+    assert_eq!(first.len(), 1024 * 1024);
+    assert!(Arc::ptr_eq(&first, &second));
+    assert_eq!(engine.get_ref("key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn history() -> Result<()> {
+    let engine = MemoryKvEngine::with_history(2);
+    for value in &["value1", "value2", "value3"] {
+        engine.set("key1".to_owned(), value.to_string())?;
+    }
+    assert_eq!(engine.get_history("key1"), ["value2", "value3"]);
+    engine.append("key1".to_owned(), "4".to_owned())?;
+    assert_eq!(engine.get_history("key1"), ["value3", "value34"]);
+
+    engine.remove("key1".to_owned())?;
+    assert!(engine.get_history("key1").is_empty());
+    engine.set("key1".to_owned(), "value5".to_owned())?;
+    assert_eq!(engine.get_history("key1"), ["value5"]);
+
+    // Nothing is kept by default
+    let engine = MemoryKvEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(engine.get_history("key1").is_empty());
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use simplekv::{migrate, KvEngine, KvStore, MemoryKvEngine, Result};
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn kvstore_to_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key42".to_owned())?;
+    store.set_with_ttl("gone".to_owned(), "value".to_owned(), Duration::from_millis(0))?;
+
+    let engine = MemoryKvEngine::new();
+    let mut reports = Vec::new();
+    let copied = migrate(&store, &engine, |copied, total| reports.push((copied, total)))?;
+    assert_eq!(copied, 99);
+    assert_eq!(reports.len(), 99);
+    assert_eq!(reports.last(), Some(&(99, 99)));
+
+    assert_eq!(engine.keys()?, store.keys()?);
+    for key_id in 0..100 {
+        let value = if key_id == 42 { None } else { Some(format!("value{}", key_id)) };
+        assert_eq!(engine.get(format!("key{}", key_id))?, value);
+    }
+    assert_eq!(engine.get("gone".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn keeps_ttls() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = KvStore::open(src_dir.path())?;
+    let dst = KvStore::open(dst_dir.path())?;
+    src.set("key1".to_owned(), "value1".to_owned())?;
+    src.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::from_secs(3600))?;
+    src.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::from_millis(300))?;
+
+    assert_eq!(migrate(&src, &dst, |_, _| {})?, 3);
+    assert_eq!(dst.ttl("key1".to_owned())?, None);
+    let ttl = dst.ttl("key2".to_owned())?.expect("key2 has a TTL");
+    assert!(ttl > Duration::from_secs(3590) && ttl <= Duration::from_secs(3600));
+    assert!(dst.ttl("key3".to_owned())?.expect("key3 has a TTL") <= Duration::from_millis(300));
+
+    std::thread::sleep(Duration::from_millis(400));
+    assert_eq!(dst.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(dst.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(dst.get("key3".to_owned())?, None);
+    Ok(())
+}
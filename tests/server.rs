@@ -0,0 +1,688 @@
+use simplekv::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use simplekv::{
+    KvClient, KvClientPool, KvEngine, KvError, KvServer, KvStore, KvStoreOptions, Request,
+    Response, Result,
+};
+use std::collections::HashSet;
+use std::net::TcpListener;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Requests over the limit should be rejected without breaking the connection.
+#[test]
+fn rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(store, pool).rate_limit(1, 2).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::connect(addr)?;
+    let mut limited = 0;
+    for _ in 0..10 {
+        if let Err(e) = client.get("key1".to_owned()) {
+            assert!(e.to_string().contains("rate limited"));
+            limited += 1;
+        }
+    }
+    assert!(limited >= 7);
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let meta = client.meta("key1".to_owned())?;
+    assert!(meta.exists);
+    assert_eq!(meta.len, 6);
+    assert!(!client.meta("key2".to_owned())?.exists);
+    Ok(())
+}
+
+// Junk on one connection gets an error reply and a close, other connections keep working.
+#[test]
+fn malformed_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut junk = TcpStream::connect(addr)?;
+    junk.write_all(b"this is not a request")?;
+    let mut resp = String::new();
+    junk.read_to_string(&mut resp)?;
+    assert!(resp.contains("protocol error"));
+
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn remove_if_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.remove_if_exists("key1".to_owned())?);
+    assert!(!client.remove_if_exists("key1".to_owned())?);
+    Ok(())
+}
+
+// A failing pair of an atomic batch leaves the whole batch unapplied.
+#[test]
+fn atomic_batch_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_value_bytes(8);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::connect(addr)?;
+    let pairs = vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "too long a value".to_owned()),
+    ];
+    let e = client.set_many(pairs, true).unwrap_err();
+    assert!(e.to_string().contains("exceeds the limit"));
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    client.set_many(vec![("key1".to_owned(), "value1".to_owned())], true)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn multiple_addresses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?.bind("127.0.0.1:0")?;
+    let addrs = server.local_addrs()?;
+    assert_eq!(addrs.len(), 2);
+    thread::spawn(move || server.serve());
+
+    KvClient::connect(addrs[0])?.set("key1".to_owned(), "value1".to_owned())?;
+    let mut client = KvClient::connect(addrs[1])?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_builder_read_timeout() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let client = KvClient::builder()
+        .read_timeout(Duration::from_millis(300))
+        .connect(addr)?;
+    assert_eq!(client.stream().read_timeout()?, Some(Duration::from_millis(300)));
+    assert_eq!(client.stream().write_timeout()?, None);
+    Ok(())
+}
+
+// A server that accepts but never replies
+#[test]
+fn client_request_timeout() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut client = KvClient::builder()
+        .read_timeout(Duration::from_millis(100))
+        .connect(addr)?;
+    let _conn = listener.accept()?;
+    match client.get("key1".to_owned()) {
+        Err(KvError::Timeout) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    // The connection is closed rather than left out of sync
+    assert!(client.get("key1".to_owned()).is_err());
+    Ok(())
+}
+
+/// Engine whose `set`s block until the gate opens.
+#[derive(Clone)]
+struct GatedEngine {
+    inner: KvStore,
+    gate: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl KvEngine for GatedEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let (open, cvar) = &*self.gate;
+        let mut open = open.lock().unwrap();
+        while !*open {
+            open = cvar.wait(open).unwrap();
+        }
+        self.inner.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.inner.remove(key)
+    }
+}
+
+// A client pipelining requests faster than they are answered gets pushed back by TCP.
+#[test]
+fn in_flight_limit() -> Result<()> {
+    const REQUESTS: usize = 400;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let gate = Arc::new((Mutex::new(false), Condvar::new()));
+    let engine = GatedEngine {
+        inner: KvStore::open(temp_dir.path())?,
+        gate: Arc::clone(&gate),
+    };
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(engine, pool).max_in_flight(2).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let stream = TcpStream::connect(addr)?;
+    let mut writer = stream.try_clone()?;
+    let written = Arc::new(AtomicBool::new(false));
+    let writer_done = Arc::clone(&written);
+    let value = "x".repeat(64 * 1024);
+    let handle = thread::spawn(move || -> Result<()> {
+        for i in 0..REQUESTS {
+            write!(writer, r#"{{"Set":{{"key":"key{}","value":"{}"}}}}"#, i, value)?;
+        }
+        writer_done.store(true, Ordering::SeqCst);
+        Ok(())
+    });
+
+    // 25MB can't all be buffered in the sockets, the server has to stop reading
+    thread::sleep(Duration::from_secs(1));
+    assert!(!written.load(Ordering::SeqCst));
+
+    *gate.0.lock().unwrap() = true;
+    gate.1.notify_all();
+    let mut reader = BufReader::new(stream);
+    let mut resp = Vec::new();
+    for _ in 0..REQUESTS {
+        resp.clear();
+        reader.read_until(b'}', &mut resp)?;
+        assert_eq!(resp, br#"{"Ok":null}"#);
+    }
+    handle.join().unwrap()?;
+    assert!(written.load(Ordering::SeqCst));
+    Ok(())
+}
+
+// Connections past the read-ahead limit are served in order on their pool thread.
+#[test]
+fn read_ahead_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let server = KvServer::new(store, pool).max_read_ahead(1).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut first = KvClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    let mut stream = TcpStream::connect(addr)?;
+    for i in 0..10 {
+        write!(stream, r#"{{"Set":{{"key":"key{}","value":"value{}"}}}}"#, i, i)?;
+    }
+    write!(stream, r#"{{"Get":{{"key":"key9"}}}}"#)?;
+    let mut reader = BufReader::new(stream);
+    let mut resp = Vec::new();
+    for _ in 0..10 {
+        resp.clear();
+        reader.read_until(b'}', &mut resp)?;
+        assert_eq!(resp, br#"{"Ok":null}"#);
+    }
+    resp.clear();
+    reader.read_until(b'}', &mut resp)?;
+    assert_eq!(resp, br#"{"Ok":"value9"}"#);
+    assert_eq!(first.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn bind_any_port() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    assert_ne!(addr.port(), 0);
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let clients = Arc::new(KvClientPool::new(addr, 2)?);
+    let ports = Arc::new(Mutex::new(HashSet::new()));
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let clients = Arc::clone(&clients);
+            let ports = Arc::clone(&ports);
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    let mut client = clients.get()?;
+                    ports.lock().unwrap().insert(client.stream().local_addr()?.port());
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(client.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let ports = ports.lock().unwrap().len();
+    assert!((1..=2).contains(&ports), "{} connections used", ports);
+
+    // A discarded connection isn't handed out again
+    let client = clients.get()?;
+    let port = client.stream().local_addr()?.port();
+    client.discard();
+    let mut client = clients.get()?;
+    assert_ne!(client.stream().local_addr()?.port(), port);
+    assert_eq!(client.get("key0-0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(3)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut a = KvClient::connect(addr)?.with_namespace("a/");
+    let mut b = KvClient::connect(addr)?.with_namespace("b/");
+    a.set("key1".to_owned(), "value-a".to_owned())?;
+    b.set("key1".to_owned(), "value-b".to_owned())?;
+    assert_eq!(a.get("key1".to_owned())?, Some("value-a".to_owned()));
+    assert_eq!(b.get("key1".to_owned())?, Some("value-b".to_owned()));
+
+    a.remove("key1".to_owned())?;
+    assert_eq!(a.get("key1".to_owned())?, None);
+    assert_eq!(b.get("key1".to_owned())?, Some("value-b".to_owned()));
+
+    // The server only knows the prefixed keys
+    let mut plain = KvClient::connect(addr)?;
+    assert_eq!(plain.get("key1".to_owned())?, None);
+    assert_eq!(plain.get("b/key1".to_owned())?, Some("value-b".to_owned()));
+    Ok(())
+}
+
+// Clients hanging up before their response is written don't take a worker down.
+#[test]
+fn client_disconnects_mid_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let value = "x".repeat(64 * 1024);
+    KvClient::connect(addr)?.set("key1".to_owned(), value.clone())?;
+    for _ in 0..20 {
+        let mut stream = TcpStream::connect(addr)?;
+        for _ in 0..10 {
+            stream.write_all(br#"{"Get":{"key":"key1"}}"#)?;
+        }
+        drop(stream);
+    }
+
+    let mut client = KvClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some(value));
+    Ok(())
+}
+
+#[test]
+fn client_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_secs(10))?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::connect(addr)?;
+    let ttl = client.ttl("key1".to_owned())?.expect("key1 has a TTL");
+    assert!(ttl > Duration::from_secs(9) && ttl <= Duration::from_secs(10));
+    assert_eq!(client.ttl("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Large responses are compressed for clients asking for it, transparently to them.
+#[test]
+fn compressed_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{:04}", key_id), format!("value{}", key_id % 10))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+    let pool = SharedQueueThreadPool::new(3)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut plain = KvClient::connect(addr)?;
+    let mut compressed = KvClient::builder().compress_responses(1024).connect(addr)?;
+    let pairs = plain.scan("key".to_owned())?;
+    assert_eq!(pairs.len(), 1000);
+    assert_eq!(pairs[42], ("key0042".to_owned(), "value2".to_owned()));
+    assert_eq!(compressed.scan("key".to_owned())?, pairs);
+    assert_eq!(compressed.get("other".to_owned())?, Some("value".to_owned()));
+
+    let scan_bytes_on_wire = |hello: bool| -> Result<usize> {
+        let stream = TcpStream::connect(addr)?;
+        let mut writer = &stream;
+        if hello {
+            writer.write_all(br#"{"Hello":{"compress_min_bytes":1024}}"#)?;
+        }
+        writer.write_all(br#"{"Scan":{"prefix":"key"}}"#)?;
+        let mut responses =
+            serde_json::Deserializer::from_reader(&stream).into_iter::<serde_json::Value>();
+        if hello {
+            responses.next().unwrap()?;
+        }
+        let start = responses.byte_offset();
+        responses.next().unwrap()?;
+        Ok(responses.byte_offset() - start)
+    };
+    let plain_bytes = scan_bytes_on_wire(false)?;
+    let compressed_bytes = scan_bytes_on_wire(true)?;
+    assert!(compressed_bytes * 2 < plain_bytes, "{} vs {}", compressed_bytes, plain_bytes);
+    Ok(())
+}
+
+// Connections can be served from memory, without any socket.
+#[test]
+fn in_memory_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let server = KvServer::new(store, SharedQueueThreadPool::new(1)?);
+
+    let requests = br#"{"Set":{"key":"key1","value":"value1"}}{"Get":{"key":"key1"}}
+        {"Remove":{"key":"key2"}}"#;
+    let mut responses = Vec::new();
+    server.serve_connection(&requests[..], &mut responses)?;
+    assert_eq!(
+        String::from_utf8(responses).unwrap(),
+        r#"{"Ok":null}{"Ok":"value1"}{"Err":"key not found"}"#
+    );
+    Ok(())
+}
+
+// A versioned set fails once somebody else wrote the key.
+#[test]
+fn set_if_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut a = KvClient::connect(addr)?;
+    let mut b = KvClient::connect(addr)?;
+    assert_eq!(a.get_versioned("key1".to_owned())?, None);
+    a.set("key1".to_owned(), "1".to_owned())?;
+    let (value, version) = a.get_versioned("key1".to_owned())?.expect("key1 is set");
+    assert_eq!(value, "1");
+
+    b.set("key1".to_owned(), "2".to_owned())?;
+    assert!(!a.set_if_version("key1".to_owned(), "2".to_owned(), version)?);
+    assert_eq!(a.get("key1".to_owned())?, Some("2".to_owned()));
+
+    let (_, version) = a.get_versioned("key1".to_owned())?.expect("key1 is set");
+    assert!(a.set_if_version("key1".to_owned(), "3".to_owned(), version)?);
+    assert_eq!(b.get("key1".to_owned())?, Some("3".to_owned()));
+    // The version is used up by the write
+    assert!(!b.set_if_version("key1".to_owned(), "4".to_owned(), version)?);
+    Ok(())
+}
+
+// Hostnames work just as for `KvClient::connect`.
+#[test]
+fn bind_hostname() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(store, pool).bind("localhost:0")?;
+    let addr = server.local_addr()?;
+    assert!(addr.ip().is_loopback());
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_max_value_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("small".to_owned(), "x".repeat(100))?;
+    store.set("large".to_owned(), "x".repeat(10_000))?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::builder().max_value_bytes(1024).connect(addr)?;
+    assert_eq!(client.get("small".to_owned())?, Some("x".repeat(100)));
+    let e = client.get("large".to_owned()).unwrap_err();
+    assert!(e.to_string().contains("value of 10000 bytes exceeds the limit of 1024"), "{}", e);
+    // Refused without breaking the connection
+    assert_eq!(client.get("missing".to_owned())?, None);
+    let e = client.get_versioned("large".to_owned()).unwrap_err();
+    assert!(e.to_string().contains("exceeds the limit of 1024"), "{}", e);
+    let e = client.scan("".to_owned()).unwrap_err();
+    assert!(e.to_string().contains("exceeds the limit of 1024"), "{}", e);
+    assert_eq!(client.scan("sm".to_owned())?, vec![("small".to_owned(), "x".repeat(100))]);
+
+    let mut unlimited = KvClient::connect(addr)?;
+    assert_eq!(unlimited.get("large".to_owned())?, Some("x".repeat(10_000)));
+    Ok(())
+}
+
+/// Engine that panics when asked for the key `panic`.
+#[derive(Clone)]
+struct PanickingEngine {
+    inner: KvStore,
+}
+
+impl KvEngine for PanickingEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.inner.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if key == "panic" {
+            panic!("handler bug");
+        }
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.inner.remove(key)
+    }
+}
+
+// A panicking handler answers with an error and closes only its own connection.
+#[test]
+fn handler_panic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = PanickingEngine {
+        inner: KvStore::open(temp_dir.path())?,
+    };
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(engine, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    match client.get("panic".to_owned()) {
+        Err(KvError::StringError(msg)) => assert_eq!(msg, "internal error: handler bug"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(client.get("key1".to_owned()).is_err());
+
+    // The only worker is free again
+    let mut client = KvClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_socket_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let client = KvClient::connect(addr)?;
+    assert!(client.stream().nodelay()?);
+
+    let mut client = KvClient::builder()
+        .nodelay(false)
+        .keepalive(true)
+        .recv_buffer_size(256 * 1024)
+        .connect(addr)?;
+    assert!(!client.stream().nodelay()?);
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        let mut keepalive: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                client.stream().as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_KEEPALIVE,
+                &mut keepalive as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_ne!(keepalive, 0);
+    }
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_raw_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::connect(addr)?;
+    let set = Request::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    assert_eq!(client.request(set)?, Response::Ok(serde_json::Value::Null));
+    match client.request(Request::Get { key: "key1".to_owned() })? {
+        Response::Ok(value) => assert_eq!(value, "value1"),
+        rsp => panic!("unexpected response: {:?}", rsp),
+    }
+    match client.request(Request::Remove { key: "key2".to_owned() })? {
+        Response::Err(msg) => assert_eq!(msg, "key not found"),
+        rsp => panic!("unexpected response: {:?}", rsp),
+    }
+    Ok(())
+}
+
+/// Repeats `op` until it doesn't fail with `KvError::WouldBlock`, returning how often it did.
+fn poll<T>(mut op: impl FnMut() -> Result<T>) -> Result<(T, usize)> {
+    let mut blocked = 0;
+    loop {
+        match op() {
+            Err(KvError::WouldBlock) => {
+                blocked += 1;
+                thread::sleep(Duration::from_millis(1));
+            }
+            res => return res.map(|value| (value, blocked)),
+        }
+    }
+}
+
+#[test]
+fn client_nonblocking() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvServer::new(store, pool).bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve());
+
+    let mut client = KvClient::connect(addr)?;
+    client.set_nonblocking(true)?;
+    // Brackets and escapes in strings don't end the response early
+    let value = "x\\\"}]".repeat(1 << 18);
+    poll(|| client.set("key1".to_owned(), value.clone()))?;
+    let (got, blocked) = poll(|| client.get("key1".to_owned()))?;
+    assert_eq!(got, Some(value));
+    assert!(blocked > 0);
+
+    assert_eq!(poll(|| client.get("key2".to_owned()))?.0, None);
+
+    client.set_nonblocking(false)?;
+    assert!(client.remove_if_exists("key1".to_owned())?);
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use simplekv::{KvEngine, KvStoreOptions, Result, ShardedKvStore};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn shard_contents(dir: &Path) -> String {
+    let mut contents = String::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().map_or(false, |ext| ext == "log") {
+            contents.push_str(&fs::read_to_string(path).unwrap());
+        }
+    }
+    contents
+}
+
+#[test]
+fn default_router() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(store.stats().keys, 100);
+    let used: Vec<_> = (0..100).map(|key_id| store.shard_of(&format!("key{}", key_id))).collect();
+    assert!((0..4).all(|shard| used.contains(&shard)));
+    drop(store);
+
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    assert_eq!(store.keys()?.len(), 100);
+    Ok(())
+}
+
+#[test]
+fn custom_router() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let router = |key: &str| if key.starts_with('a') { 0 } else { 1 };
+    let store =
+        ShardedKvStore::open_with_router(temp_dir.path(), 2, KvStoreOptions::default(), router)?;
+    for key in &["a1", "a2", "abc", "b1", "c1"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.flush()?;
+
+    let shard0 = shard_contents(&temp_dir.path().join("shard-0"));
+    let shard1 = shard_contents(&temp_dir.path().join("shard-1"));
+    for key in &["a1", "a2", "abc"] {
+        assert!(shard0.contains(&format!("value-{}", key)));
+        assert!(!shard1.contains(&format!("value-{}", key)));
+    }
+    for key in &["b1", "c1"] {
+        assert!(!shard0.contains(&format!("value-{}", key)));
+        assert!(shard1.contains(&format!("value-{}", key)));
+    }
+    assert_eq!(store.get("abc".to_owned())?, Some("value-abc".to_owned()));
+    Ok(())
+}
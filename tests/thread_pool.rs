@@ -2,7 +2,8 @@ use simplekv::Result;
 use simplekv::thread_pool::{SharedQueueThreadPool, ThreadPool};
 use crossbeam_utils::sync::WaitGroup;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
+use std::thread;
 
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
     const TASK_NUM: usize = 20;
@@ -32,3 +33,21 @@ fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
     let pool = SharedQueueThreadPool::new(4)?;
     spawn_counter(pool)
 }
+
+#[test]
+fn shared_queue_thread_pool_worker_names() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let (tx, rx) = mpsc::channel();
+    pool.spawn(move || panic!("worker panicked"));
+    for _ in 0..4 {
+        let tx = tx.clone();
+        pool.spawn(move || {
+            tx.send(thread::current().name().map(str::to_owned)).unwrap();
+        });
+    }
+    for _ in 0..4 {
+        let name = rx.recv().unwrap().expect("worker has no name");
+        assert!(name == "kv-worker-0" || name == "kv-worker-1", "unexpected name {}", name);
+    }
+    Ok(())
+}